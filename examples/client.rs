@@ -1,62 +1,104 @@
-use tokio::io::{AsyncWriteExt, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncWriteExt, AsyncReadExt};
 use tokio::net::{TcpStream, TcpListener};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc;
 
 use std::error::Error;
 use std::io::{self, Write}; // Use the tokio variant later
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::sync::{Arc, Mutex};
 // use std::cell::Cell; Perhaps use it instead of just reasigning the username.
 
 use chat_server::{Serialize, Deserialize, Packet};
 use chat_server::request::Command; 
 use chat_server::respond::Response;
+use chat_server::search::{SearchMode, Page};
+use chat_server::presence::Presence;
+use chat_server::ban::BanTarget;
 
 
-type Users    = HashMap<String, TcpStream>;
-type Messages = Arc<Mutex<HashMap<String, Vec<String>>>>;
+type Users    = HashMap<String, OwnedWriteHalf>;
+type Messages = Arc<Mutex<Inbox>>;
+// Id, timestamp, text and when it disappears
+type Received = (u64, u64, String, Option<u64>);
+
+#[derive(Default)]
+struct Inbox {
+    messages: HashMap<String, Vec<Received>>,
+    seen: HashSet<(String, u64)>, // Retries reuse the id, so this keeps us from showing a message twice
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     println!("connecting to host");
-    let mut stream = TcpStream::connect("127.0.0.1:6142").await?;
+    let (mut server, mut stream) = TcpStream::connect("127.0.0.1:6142").await?.into_split();
     println!("connected");
 
+    // The server can send us things we didn't ask for, those are printed right away and
+    // everything else is handed to the loop below as the answer to our last command.
+    let (replies_tx, mut replies) = mpsc::unbounded_channel();
+    let messages: Messages = Arc::new(Mutex::new(Inbox::default()));
+    let inbox = messages.clone();
+    tokio::spawn(async move {
+        while let Ok(data) = response(&mut server).await {
+            match data.deserialize() {
+                Some(Response::Renamed(old, new)) => println!("{} is now known as {}", old, new),
+                Some(Response::PresenceChanged(name, presence, status)) => println!("{} is {:?} {}", name, presence, status),
+                Some(Response::Kicked(reason)) => println!("Disconnected: {}", reason),
+                Some(Response::Broadcast(msg)) => println!("[server] {}", msg),
+                // Messages we haven't looked at yet are changed in place
+                Some(Response::MessageEdited(id, msg)) => {
+                    println!("Message {} was edited: {}", id, msg);
+                    let mut inbox = inbox.lock().unwrap();
+                    for entry in inbox.messages.values_mut().flatten().filter(|entry| entry.0 == id) {
+                        entry.2 = msg.clone();
+                    }
+                },
+                Some(Response::Reactions(id, reactions)) => {
+                    let reactions: Vec<String> = reactions.iter()
+                        .map(|(emoji, names)| format!("{} {}", emoji, names.join(", ")))
+                        .collect();
+                    println!("Reactions to message {}: {}", id, reactions.join("; "));
+                },
+                Some(Response::MessageDeleted(id)) => {
+                    println!("Message {} was deleted", id);
+                    for messages in inbox.lock().unwrap().messages.values_mut() {
+                        messages.retain(|entry| entry.0 != id);
+                    }
+                },
+                res => if replies_tx.send(res).is_err() { break; },
+            }
+        }
+    });
+
     let mut users: Users    = HashMap::new();
-    let messages:  Messages = Arc::new(Mutex::new(HashMap::new()));
-    let mut username: Option<String> = None;
+    let mut username: Option<(String, SocketAddr)> = None;
     loop {
 
-        stream.writable().await?;
         let messages = messages.clone();
 
         let command = command_from_stdin();
-          
-        // If the command is a message, check and see if we already have a connection with the end user of the message
-        // if not, later on we ask the server for the ip and start the connection.
-        if let Command::Message(ref name, ref msg) = command {
-            match users.get_mut(name) {
-                Some(stream) => {
-                    if let None = username { continue; }
-                    else if let Some(ref username) = username {
-                        let cmd = Command::Message(username.to_string(), msg.to_string()).serialize(); // Replace "me" with name of user
-                        stream.write_all(&Packet::to_byte_vec(cmd)).await?;
-                        stream.write_all(&[0, 0, 0, 0]).await?;  // Unessecary extra sys call
-                        continue;
-                    }
-                },
-                None => (),
-            }
-        }
 
         if let Command::Show = command {
-            let mut messages = messages.lock().unwrap();
-            for (name, msg) in messages.drain() {
+            let mut inbox = messages.lock().unwrap();
+            let now = chat_server::now();
+            for (name, msg) in inbox.messages.drain() {
                 println!("{}: ", name);
-                msg.iter().for_each(|m| println!("{}", m));
+                msg.iter()
+                    .filter(|(_, _, _, expires)| expires.is_none_or(|expires| expires > now))
+                    .for_each(|(id, timestamp, m, _)| println!("[{}] {} ({})", timestamp, m, id));
                 println!("-------------------");
             }
-            continue;
+            // Then ask the server for anything that was sent while we were offline
+        }
+
+        // Messages always go through the server first, it timestamps them and tells us where the
+        // end user is. If we already have a connection with the end user it is reused below.
+        if let Command::Message(..) = command {
+            if username.is_none() { continue; }
         }
 
         // send request
@@ -68,15 +110,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
 
         // get respond
-        let data = response(&mut stream).await?;
-        let data: Option<Response> = data.deserialize();
+        let data = replies.recv().await.ok_or("lost the connection to the server")?;
 
         match data {
             None => (),
             Some(Response::Login(name, addr))  => {
                 println!("Logged in as {}", name);
                 println!("At {}", addr);
-                username = Some(name);
+                username = Some((name, addr));
 
                 // Start listening for connections
                 let listener = TcpListener::bind(addr).await.unwrap();
@@ -85,9 +126,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         let (socket, _) = listener.accept().await.unwrap();
                         let messages = messages.clone();
                         tokio::spawn(async move {
-                            match process_socket(socket, messages).await {
-                                Err(e) => println!("{:?}", e),
-                                Ok(()) => (),
+                            if let Err(e) = process_socket(socket, messages).await {
+                                println!("{:?}", e);
                             }
                         });
                     }
@@ -95,28 +135,118 @@ async fn main() -> Result<(), Box<dyn Error>> {
             },
             Some(Response::Logout) => println!("Logged out"),
             Some(Response::Exit)   => println!("You exited"), // Will not show
-            Some(Response::Search(users)) => {
+            Some(Response::Search(users, next)) => {
                 // Prints the users name and address
                 println!("-------------------");
                 users.iter()
-                    .for_each(|(name, addr)| {
-                        println!("name: {}", name);
-                        println!("Address: {}", addr);
+                    .for_each(|user| {
+                        println!("name: {}", user.name);
+                        println!("Address: {}", user.addr);
+                        match user.status.as_str() {
+                            "" => println!("{:?}", user.presence),
+                            status => println!("{:?}: {}", user.presence, status),
+                        }
                         println!("-------------------");
-                    })
+                    });
+                if users.is_empty() { println!("Nobody found"); }
+                if let Some(next) = next { println!("More results, add \"+{}\" to see them", next); }
             },
-            Some(Response::Message(name, msg, addr)) => {
-                let mut stream = TcpStream::connect(addr).await?;
-                stream.writable().await?;
-
-                if let None = username { continue; }
-                else if let Some(ref username) = username {
-                    let cmd = Command::Message(username.to_string(), msg).serialize();
-                    stream.write_all(&Packet::to_byte_vec(cmd)).await?;
-                    stream.write_all(&[0, 0, 0, 0]).await?;  // Unessecary extra sys call
-                    users.insert(name, stream);
+            Some(Response::Matches(matches, next)) => {
+                // The match is marked with a star, between the messages around it
+                println!("-------------------");
+                for found in &matches {
+                    found.before.iter().for_each(|r| println!("  [{}] {}: {}", r.timestamp, r.from, r.msg));
+                    println!("* [{}] {}: {}", found.record.timestamp, found.record.from, found.record.msg);
+                    found.after.iter().for_each(|r| println!("  [{}] {}: {}", r.timestamp, r.from, r.msg));
+                    println!("-------------------");
                 }
-            }
+                if matches.is_empty() { println!("No messages found"); }
+                if let Some(next) = next { println!("More results, add \"+{}\" to see them", next); }
+            },
+            Some(Response::Message(name, id, timestamp, msg, addr, expires)) => {
+                let (me, my_addr) = match username {
+                    Some(ref user) => user.clone(),
+                    None => continue,
+                };
+                if !users.contains_key(&name) {
+                    let stream = TcpStream::connect(addr).await?;
+                    let (mut reader, writer) = stream.into_split();
+                    // The end user answers on the same connection once it has the message
+                    tokio::spawn(async move {
+                        while let Ok(bytes) = request(&mut reader).await {
+                            match bytes.deserialize() {
+                                Some(Response::Delivered(id)) => println!("Message {} delivered", id),
+                                _ => break,
+                            }
+                        }
+                    });
+                    users.insert(name.clone(), writer);
+                }
+                let stream = users.get_mut(&name).unwrap();
+                let res = Response::Message(me, id, timestamp, msg, my_addr, expires).serialize();
+                stream.write_all(&Packet::to_byte_vec(res)).await?;
+                stream.write_all(&[0, 0, 0, 0]).await?;  // Unessecary extra sys call
+            },
+            Some(Response::Delivered(id)) => println!("Message {} delivered", id),
+            Some(Response::Failed(id, reason)) => println!("Message {} failed: {}", id, reason),
+            Some(Response::History(records)) => {
+                println!("-------------------");
+                records.iter()
+                    .for_each(|r| println!("[{}] {}: {} ({})", r.timestamp, r.from, r.msg, r.id));
+                println!("-------------------");
+            },
+            Some(Response::Queued(id)) => println!("Message {} will be delivered when they log in", id),
+            Some(Response::Error(e)) => println!("Error: {}", e),
+            Some(Response::Nick(name)) => {
+                println!("You are now known as {}", name);
+                if let Some((_, addr)) = username {
+                    username = Some((name, addr));
+                }
+            },
+            Some(Response::Renamed(..)) => (),
+            Some(Response::PresenceChanged(..)) => (),
+            Some(Response::Kicked(..)) => (),
+            Some(Response::Broadcast(..)) => (),
+            Some(Response::MessageEdited(..)) => (),
+            Some(Response::MessageDeleted(..)) => (),
+            Some(Response::Reactions(..)) => (),
+            // Only admin connections get these
+            Some(Response::Sessions(..)) | Some(Response::Stats(..)) => (),
+            Some(Response::Ok) => println!("Done"),
+            Some(Response::Connections(total, addrs)) => {
+                println!("{} connections", total);
+                addrs.iter().for_each(|(ip, n)| println!("{}: {}", ip, n));
+            },
+            Some(Response::Bans(bans)) => {
+                println!("-------------------");
+                for ban in bans {
+                    let target = match ban.target {
+                        BanTarget::Nick(name) => name,
+                        BanTarget::Ip(range) => range.to_string(),
+                    };
+                    match ban.expires {
+                        Some(expires) => println!("{} until {}", target, expires),
+                        None => println!("{}", target),
+                    }
+                }
+                println!("-------------------");
+            },
+            Some(Response::Blocked(names)) => println!("Blocked: {}", names.join(", ")),
+            Some(Response::Contacts(contacts)) => {
+                println!("-------------------");
+                for (name, presence, status) in contacts {
+                    println!("{} is {:?} {}", name, presence, status);
+                }
+                println!("-------------------");
+            },
+            Some(Response::Status(presence, status)) => println!("You are {:?} {}", presence, status),
+            Some(Response::Unread(senders)) => {
+                for (name, records) in senders {
+                    println!("{} ({} unread): ", name, records.len());
+                    records.iter().for_each(|r| println!("[{}] {}", r.timestamp, r.msg));
+                    println!("-------------------");
+                }
+            },
         }
     }
     Ok(())
@@ -125,30 +255,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
 async fn process_socket(mut socket: TcpStream, messages: Messages) -> Result<(), Box<dyn Error>> {
     loop {
         let bytes = request(&mut socket).await?;
-        let command = bytes.deserialize().unwrap();
-        match command {
-            Command::Message(name, msg) => {
-                let mut messages = messages.lock().unwrap();
-                match messages.get_mut(&name) {
-                    Some(user) => user.push(msg),
-                    None       => {
-                        messages.insert(name, vec![msg]);
-                    },
+        if let Some(Response::Message(name, id, timestamp, msg, _, expires)) = bytes.deserialize() {
+            {
+                let mut inbox = messages.lock().unwrap();
+                if inbox.seen.insert((name.clone(), id)) {
+                    inbox.messages.entry(name).or_default().push((id, timestamp, msg, expires));
                 }
-            },
-            _ => ()
+            }
+            let ack = Packet::to_byte_vec(Response::Delivered(id).serialize());
+            socket.write_all(&ack).await?;
+            socket.write_all(&[0, 0, 0, 0]).await?;  // Unessecary extra sys call
         }
     }
 }
 
 // NOTE: DRY code!!
-async fn request(socket: &mut TcpStream) -> Result<Vec<Packet>, Box<dyn Error>> {
-    socket.readable().await?;
+async fn request<R: AsyncRead + Unpin>(socket: &mut R) -> Result<Vec<Packet>, Box<dyn Error>> {
     let mut bytes = Vec::new();
     loop {
         let amount = match socket.read_u32().await {
             Ok(0) => break,
-            Ok(n) if n == 0 => break,
             Ok(n) => n,
             // Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break, // wut is this?
             Err(e)     => return Err(Box::new(e)),
@@ -170,16 +296,23 @@ async fn request(socket: &mut TcpStream) -> Result<Vec<Packet>, Box<dyn Error>>
 
 // NOTE: DRY CODE!!
 fn get_buffer(amount: u32) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(amount as usize);
-    for _ in 0..amount {
-        buf.push(0);
-    }
-    buf
+    vec![0; amount as usize]
+}
+
+// Ids have to be unique across the whole server, a randomly seeded hash of the current time
+// makes running into someone else's unlikely enough
+fn message_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(std::time::SystemTime::now()
+                      .duration_since(std::time::UNIX_EPOCH)
+                      .map(|d| d.as_nanos())
+                      .unwrap_or(0));
+    hasher.finish()
 }
 
 fn command_from_stdin() -> Command {
     let mut command = None;
-    while let None = command {
+    while command.is_none() {
         prompt();
         let mut msg = String::new();
         io::stdin().read_line(&mut msg).unwrap();
@@ -201,36 +334,130 @@ fn string_to_command(string: String) -> Option<Command> {
         Some("login")  => {
             let nickname = String::from(string.next().unwrap_or(""));
             let ip:Vec<Option<u8>> = string.next()?
-                .split('.').map(|x| u8::from_str_radix(x, 10).ok()).collect();
+                .split('.').map(|x| x.parse::<u8>().ok()).collect();
             let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(ip[0]?, ip[1]?, ip[2]?, ip[3]?)), 8080);
-            Some(Command::Login(nickname, addr))
+            // "login bob 127.0.0.2 hunter2", the first login with a name sets its password
+            let password = string.next().map(str::to_string);
+            Some(Command::Login(nickname, addr, password))
         },
         Some("logout") => Some(Command::Logout),
         Some("exit")   => Some(Command::Exit),
-        Some("search") => Some(Command::Search(String::from(string
-                                                            .next()
-                                                            .unwrap_or("")))),
+        Some("search") => {
+            let mut pattern = string.next().unwrap_or("");
+            let mode = match pattern {
+                "-p" => Some(SearchMode::Prefix),
+                "-g" => Some(SearchMode::Glob),
+                "-s" => Some(SearchMode::Substring),
+                "-f" => Some(SearchMode::Fuzzy),
+                _    => None,
+            };
+            if mode.is_some() {
+                pattern = string.next().unwrap_or("");
+            }
+            let mode = mode.unwrap_or(if pattern.contains(['*', '?']) { SearchMode::Glob } else { SearchMode::Exact });
+            // "+20" continues from the 20th result
+            let offset = string.next()
+                .and_then(|n| n.strip_prefix('+'))
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(0);
+            Some(Command::Search(mode, String::from(pattern), Page { offset, limit: 10 }))
+        },
         Some("msg")    => {
             let name = string.next()?.to_string();
             let msg = string.fold("".to_string(), |acc, s| acc + s + " ").trim().to_string();
-            Some(Command::Message(name, msg))
+            Some(Command::Message(name, message_id(), msg, None))
+        },
+        // Like msg, but the message is deleted everywhere after the given number of seconds
+        Some("expiring") => {
+            let secs = string.next()?.parse().ok()?;
+            let name = string.next()?.to_string();
+            let msg = string.fold("".to_string(), |acc, s| acc + s + " ").trim().to_string();
+            Some(Command::Message(name, message_id(), msg, Some(secs)))
         },
+        Some("edit")   => {
+            let id = string.next()?.parse().ok()?;
+            let msg = string.fold("".to_string(), |acc, s| acc + s + " ").trim().to_string();
+            Some(Command::Edit(id, msg))
+        },
+        Some("delete") => Some(Command::Delete(string.next()?.parse().ok()?)),
+        Some("react")  => Some(Command::React { message_id: string.next()?.parse().ok()?, emoji: string.next()?.to_string() }),
+        Some("unreact") => Some(Command::Unreact { message_id: string.next()?.parse().ok()?, emoji: string.next()?.to_string() }),
         Some("show")   => Some(Command::Show),
+        Some("nick")   => Some(Command::Nick(string.next()?.to_string())),
+        Some("contacts") => Some(Command::Contacts),
+        Some("block")    => Some(Command::Block(string.next()?.to_string())),
+        Some("unblock")  => Some(Command::Unblock(string.next()?.to_string())),
+        Some("blocked")  => Some(Command::Blocked),
+        Some("contact") => match (string.next()?, string.next()?) {
+            ("add", name)    => Some(Command::AddContact(name.to_string())),
+            ("remove", name) => Some(Command::RemoveContact(name.to_string())),
+            _                => None,
+        },
+        Some("status") => {
+            let presence = match string.next()? {
+                "online"    => Presence::Online,
+                "away"      => Presence::Away,
+                "busy"      => Presence::Busy,
+                "invisible" => Presence::Invisible,
+                _           => return None,
+            };
+            let status = string.fold("".to_string(), |acc, s| acc + s + " ").trim().to_string();
+            Some(Command::Status(presence, status))
+        },
+        Some("oper")   => Some(Command::Oper(string.next()?.to_string())),
+        Some("grant")  => Some(Command::Grant(string.next()?.to_string())),
+        Some("kick")   => Some(Command::Kick(string.next()?.to_string())),
+        Some("bans")   => Some(Command::Bans),
+        Some("connections") => Some(Command::Connections),
+        // "ban nick bob 60" bans bob for a minute, leaving out the seconds bans for good
+        Some("ban")    => Some(Command::Ban(ban_target(string.next()?, string.next()?)?, string.next().and_then(|n| n.parse().ok()))),
+        Some("unban")  => Some(Command::Unban(ban_target(string.next()?, string.next()?)?)),
+        Some("mute")   => Some(Command::Mute(string.next()?.to_string(), string.next().and_then(|n| n.parse().ok()))),
+        Some("unmute") => Some(Command::Unmute(string.next()?.to_string())),
+        Some("history") => {
+            let peer = string.next()?.to_string();
+            let limit = string.next().and_then(|n| n.parse().ok()).unwrap_or(20);
+            // "history bob 20 <timestamp> <id>" continues from before the message given
+            let before = string.next().and_then(|t| t.parse().ok())
+                .map(|timestamp| (timestamp, string.next().and_then(|id| id.parse().ok()).unwrap_or(0)));
+            Some(Command::History { peer, before, limit })
+        },
+        // "find @bob lunch +10" looks for lunch in the conversation with bob, from the 10th result
+        Some("find") => {
+            let mut peer = None;
+            let mut offset = 0;
+            let mut words = Vec::new();
+            for word in string {
+                match (word.strip_prefix('@'), word.strip_prefix('+').and_then(|n| n.parse().ok())) {
+                    (Some(name), _) => peer = Some(name.to_string()),
+                    (_, Some(n)) => offset = n,
+                    _ => words.push(word),
+                }
+            }
+            Some(Command::SearchMessages { query: words.join(" "), peer, since: None, page: Page { offset, limit: 10 } })
+        },
+        Some("export") => Some(Command::Export(string.next()?.to_string())),
         _              => None,
     }
 }
 
+fn ban_target(kind: &str, target: &str) -> Option<BanTarget> {
+    match kind {
+        "nick" => Some(BanTarget::Nick(target.to_string())),
+        "ip"   => Some(BanTarget::Ip(target.parse().ok()?)),
+        _      => None,
+    }
+}
+
 // NOTE: dry code, is also in main
-async fn response(stream: &mut TcpStream) -> Result<Vec<Packet>, String> {
-    stream.readable().await.or(Err(format!("can't become ready")))?;
+async fn response(stream: &mut OwnedReadHalf) -> Result<Vec<Packet>, String> {
     let mut bytes = Vec::new();
     loop {
         let amount = match stream.read_u32().await {
             Ok(0) => break,
-            Ok(n) if n == 0 => break,
             Ok(n) => n,
             // Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break, // wut is this?
-            Err(_)     => return Err(format!("error reading size")),
+            Err(_)     => return Err("error reading size".to_string()),
         };
         let mut buffer = get_buffer(amount);
         match stream.read_exact(&mut buffer).await {
@@ -241,7 +468,7 @@ async fn response(stream: &mut TcpStream) -> Result<Vec<Packet>, String> {
                 bytes.push(packet)
             },
             // Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-            Err(_) => return Err(format!("error when reading")),
+            Err(_) => return Err("error when reading".to_string()),
         }
     }
     Ok(bytes)
@@ -0,0 +1,40 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+
+use crate::json::Value;
+
+/// Append only log of security relevant events, one JSON object per line:
+///
+/// ```text
+/// {"time":1700000000000,"event":"login","peer":"10.0.0.7:51234","nick":"alice"}
+/// ```
+pub struct Audit {
+    // `None` when auditing is turned off
+    file: Option<File>,
+}
+
+impl Audit {
+    /// Opens `path` for appending, an empty path turns auditing off.
+    pub fn open(path: &str) -> io::Result<Audit> {
+        if path.is_empty() { return Ok(Audit { file: None }); }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Audit { file: Some(file) })
+    }
+
+    /// Writes the event right away, so nothing is lost if the server goes down after.
+    pub fn record(&mut self, event: &str, peer: Option<SocketAddr>, fields: Vec<(&str, Value)>) -> io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let mut entry = vec![
+            ("time", Value::from(crate::now())),
+            ("event", Value::from(event)),
+            ("peer", Value::from(peer.map(|peer| peer.to_string()))),
+        ];
+        entry.extend(fields);
+        // The whole line in one write, so lines from different connections can't interleave
+        file.write_all(format!("{}\n", Value::object(entry)).as_bytes())
+    }
+}
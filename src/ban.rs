@@ -0,0 +1,232 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::nick;
+use crate::{Serialize, Deserialize, Packet, string_packet, u64_packet, Nickname, Timestamp};
+
+/// A block of addresses written as `10.0.0.0/8`, a plain address is a range of one. The bits
+/// past the prefix are always zero, so `10.1.2.3/8` is read as `10.0.0.0/8`, and ipv4 ranges
+/// written as ipv6 ones, like `::ffff:10.0.0.0/104`, are read as the ipv4 range they are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpRange {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Peers on a dual stack listener show up as ipv4-mapped ipv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<IpRange, ()> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| ())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| ())?,
+            None => max,
+        };
+        if prefix > max { return Err(()); }
+        let v4 = |addr: Ipv4Addr, prefix: u8| {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpRange { addr: Ipv4Addr::from(u32::from(addr) & mask).into(), prefix }
+        };
+        Ok(match addr {
+            IpAddr::V4(addr) => v4(addr, prefix),
+            IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+                Some(addr) if prefix >= 96 => v4(addr, prefix - 96),
+                _ => {
+                    let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                    IpRange { addr: Ipv6Addr::from(u128::from(addr) & mask).into(), prefix }
+                },
+            },
+        })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BanTarget {
+    Nick(Nickname),
+    Ip(IpRange),
+}
+
+impl BanTarget {
+    /// The same for every way of writing the target, so there is one ban per key.
+    pub fn key(&self) -> String {
+        match self {
+            BanTarget::Nick(name) => format!("nick {}", nick::key(name)),
+            BanTarget::Ip(range) => format!("ip {}", range),
+        }
+    }
+
+    fn same(&self, other: &BanTarget) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Serialize for BanTarget {
+    fn serialize(&self) -> Vec<Packet> {
+        match self {
+            BanTarget::Nick(name) => vec![string_packet(name, 0)],
+            BanTarget::Ip(range) => vec![string_packet(&range.to_string(), 1)],
+        }
+    }
+}
+
+impl Deserialize<BanTarget> for Packet {
+    fn deserialize(&self) -> Option<BanTarget> {
+        let text: String = self.deserialize()?;
+        match self.data_type {
+            0 => Some(BanTarget::Nick(text)),
+            1 => Some(BanTarget::Ip(text.parse().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// When the ban is lifted, `None` for never.
+    pub expires: Option<Timestamp>,
+}
+
+impl Ban {
+    fn active(&self, now: Timestamp) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+impl Serialize for Ban {
+    fn serialize(&self) -> Vec<Packet> {
+        let mut packets = self.target.serialize();
+        packets.push(u64_packet(self.expires.unwrap_or(0), 0));
+        packets
+    }
+}
+
+impl Deserialize<Ban> for [Packet] {
+    fn deserialize(&self) -> Option<Ban> {
+        let target = self.first()?.deserialize()?;
+        let expires: u64 = self.get(1)?.deserialize()?;
+        Some(Ban { target, expires: if expires == 0 { None } else { Some(expires) } })
+    }
+}
+
+/// Number of packets a serialized `Ban` takes up.
+pub const BAN_PACKETS: usize = 2;
+
+/// A list of bans with at most one for each target. Storage backends keep it however they
+/// like, see `storage::Storage::bans`.
+#[derive(Debug, Clone, Default)]
+pub struct Bans {
+    bans: Vec<Ban>,
+}
+
+impl Bans {
+    pub fn new(bans: Vec<Ban>) -> Bans {
+        let mut list = Bans::default();
+        for ban in bans {
+            list.add(ban);
+        }
+        list
+    }
+
+    /// Adds the ban, replacing any earlier ban of the same target.
+    pub fn add(&mut self, ban: Ban) {
+        self.bans.retain(|b| !b.target.same(&ban.target));
+        self.bans.push(ban);
+    }
+
+    /// Lifts the ban on `target`, returns whether there was one.
+    pub fn remove(&mut self, target: &BanTarget) -> bool {
+        let before = self.bans.len();
+        self.bans.retain(|b| !b.target.same(target));
+        self.bans.len() != before
+    }
+
+    /// Forgets the bans that have run out.
+    pub fn prune(&mut self, now: Timestamp) {
+        self.bans.retain(|b| b.active(now));
+    }
+
+    /// The ban keeping `name` out, if any.
+    pub fn nick(&self, name: &str, now: Timestamp) -> Option<&Ban> {
+        let key = nick::key(name);
+        self.bans.iter()
+            .filter(|b| b.active(now))
+            .find(|b| matches!(&b.target, BanTarget::Nick(n) if nick::key(n) == key))
+    }
+
+    /// The ban keeping connections from `ip` out, if any.
+    pub fn ip(&self, ip: IpAddr, now: Timestamp) -> Option<&Ban> {
+        self.bans.iter()
+            .filter(|b| b.active(now))
+            .find(|b| matches!(&b.target, BanTarget::Ip(range) if range.contains(ip)))
+    }
+
+    pub fn active(&self, now: Timestamp) -> Vec<Ban> {
+        self.bans.iter().filter(|b| b.active(now)).cloned().collect()
+    }
+
+    /// Every ban, including the ones that have run out but weren't pruned yet.
+    pub fn all(&self) -> &[Ban] {
+        &self.bans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_are_masked_when_parsed() {
+        assert_eq!(range("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(BanTarget::Ip(range("10.1.2.3/8")).key(), BanTarget::Ip(range("10.0.0.0/8")).key());
+        assert_eq!(range("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert_eq!(range("::ffff:10.1.2.3/104").to_string(), "10.0.0.0/8");
+        assert_eq!(range("10.0.0.1").to_string(), "10.0.0.1/32");
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("bob".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn ranges_contain_their_addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(range("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!range("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(range("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert!(range("0.0.0.0/0").contains(ip("1.2.3.4")));
+        assert!(range("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!range("2001:db8::/32").contains(ip("10.0.0.1")));
+    }
+}
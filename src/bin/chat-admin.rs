@@ -0,0 +1,202 @@
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use chat_server::{Serialize, Deserialize, Packet};
+use chat_server::request::Command;
+use chat_server::respond::Response;
+use chat_server::ban::BanTarget;
+use chat_server::error::Error as ChatError;
+use chat_server::history::Record;
+use chat_server::json::Value;
+
+const USAGE: &str = "\
+usage: chat-admin [--server host:port] [--token token] <command>
+
+The token can also be given in CHAT_ADMIN_TOKEN.
+
+commands:
+    users                          everyone logged in, with stats for their session
+    kick <nick>
+    ban nick|ip <target> [secs]    for good unless given a number of seconds
+    unban nick|ip <target>
+    bans
+    broadcast <text>               sent to everyone online
+    stats
+    export <nick> [jsonl|text]     every message the user sent or received, to stdout
+    import <file>                  messages from a JSON Lines export";
+
+// Messages sent to the server per import command
+const IMPORT_BATCH: usize = 1000;
+
+enum Task {
+    Command(Command),
+    Export(String, Format),
+    Import(String),
+}
+
+enum Format {
+    JsonLines,
+    Text,
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("chat-admin: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut server = "127.0.0.1:6142".to_string();
+    let mut token = std::env::var("CHAT_ADMIN_TOKEN").ok();
+    while let Some(flag) = args.peek().filter(|arg| arg.starts_with("--")).cloned() {
+        args.next();
+        match flag.as_str() {
+            "--server" => server = args.next().ok_or(USAGE)?,
+            "--token" => token = Some(args.next().ok_or(USAGE)?),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let args: Vec<String> = args.collect();
+    let task = task(&args).ok_or(USAGE)?;
+    let token = token.ok_or("no admin token, use --token or CHAT_ADMIN_TOKEN")?;
+
+    let mut stream = TcpStream::connect(&server)?;
+    match send(&mut stream, Command::Admin(token))? {
+        Response::Ok => (),
+        Response::Error(e) => return Err(e.to_string().into()),
+        res => return Err(format!("unexpected response: {:?}", res).into()),
+    }
+    let done = match task {
+        Task::Command(command) => send(&mut stream, command).and_then(print),
+        Task::Export(name, format) => export(&mut stream, name, format),
+        Task::Import(path) => import(&mut stream, &path),
+    };
+    let _ = send_only(&mut stream, Command::Exit);
+    done
+}
+
+fn task(args: &[String]) -> Option<Task> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["export", nick] | ["export", nick, "jsonl"] => Some(Task::Export(nick.to_string(), Format::JsonLines)),
+        ["export", nick, "text"] => Some(Task::Export(nick.to_string(), Format::Text)),
+        ["import", path] => Some(Task::Import(path.to_string())),
+        _ => command(&args).map(Task::Command),
+    }
+}
+
+fn command(args: &[&str]) -> Option<Command> {
+    match args[..] {
+        ["users"] => Some(Command::Sessions),
+        ["kick", nick] => Some(Command::Kick(nick.to_string())),
+        ["ban", kind, target] => Some(Command::Ban(ban_target(kind, target)?, None)),
+        ["ban", kind, target, secs] => Some(Command::Ban(ban_target(kind, target)?, Some(secs.parse().ok()?))),
+        ["unban", kind, target] => Some(Command::Unban(ban_target(kind, target)?)),
+        ["bans"] => Some(Command::Bans),
+        ["broadcast", ref text @ ..] if !text.is_empty() => Some(Command::Broadcast(text.join(" "))),
+        ["stats"] => Some(Command::Stats),
+        _ => None,
+    }
+}
+
+fn ban_target(kind: &str, target: &str) -> Option<BanTarget> {
+    match kind {
+        "nick" => Some(BanTarget::Nick(target.to_string())),
+        "ip"   => Some(BanTarget::Ip(target.parse().ok()?)),
+        _      => None,
+    }
+}
+
+fn export(stream: &mut TcpStream, name: String, format: Format) -> Result<(), Box<dyn Error>> {
+    let records = match send(stream, Command::Export(name))? {
+        Response::History(records) => records,
+        Response::Error(e) => return Err(e.to_string().into()),
+        res => return Err(format!("unexpected response: {:?}", res).into()),
+    };
+    for record in records {
+        match format {
+            Format::JsonLines => println!("{}", record.to_json()),
+            Format::Text => println!("{}", record),
+        }
+    }
+    Ok(())
+}
+
+fn import(stream: &mut TcpStream, path: &str) -> Result<(), Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let mut records = Vec::new();
+    for (n, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let record = Value::parse(line).as_ref().and_then(Record::from_json)
+            .ok_or_else(|| format!("{}:{}: not a message", path, n + 1))?;
+        records.push(record);
+    }
+    for batch in records.chunks(IMPORT_BATCH) {
+        // Big archives run into the server's rate limit, the batch is sent again once it allows
+        loop {
+            match send(stream, Command::Import(batch.to_vec()))? {
+                Response::Ok => break,
+                Response::Error(ChatError::RateLimited(wait)) => thread::sleep(Duration::from_millis(wait)),
+                Response::Error(e) => return Err(e.to_string().into()),
+                res => return Err(format!("unexpected response: {:?}", res).into()),
+            }
+        }
+    }
+    println!("imported {} messages", records.len());
+    Ok(())
+}
+
+fn send_only(stream: &mut TcpStream, command: Command) -> Result<(), Box<dyn Error>> {
+    stream.write_all(&Packet::to_byte_vec(command.serialize()))?;
+    stream.write_all(&[0, 0, 0, 0])?;
+    Ok(())
+}
+
+fn send(stream: &mut TcpStream, command: Command) -> Result<Response, Box<dyn Error>> {
+    send_only(stream, command)?;
+    // Anything pushed to every connection, like broadcasts, isn't the answer we are after
+    loop {
+        let frame = Packet::read_frame(stream)?.ok_or("the server closed the connection")?;
+        match frame.deserialize() {
+            Some(Response::Broadcast(_)) => continue,
+            Some(res) => return Ok(res),
+            None => return Err("could not decode the server's response".into()),
+        }
+    }
+}
+
+fn print(res: Response) -> Result<(), Box<dyn Error>> {
+    match res {
+        Response::Ok => println!("ok"),
+        Response::Error(e) => return Err(e.to_string().into()),
+        Response::Sessions(sessions) => {
+            println!("{:<20} {:<22} {:<10} {:>8} {:>8} {:>14}", "nick", "peer", "presence", "commands", "messages", "logged in");
+            for s in sessions {
+                let name = if s.operator { format!("{} (op)", s.name) } else { s.name };
+                println!("{:<20} {:<22} {:<10} {:>8} {:>8} {:>14}",
+                         name, s.peer.to_string(), format!("{:?}", s.presence), s.commands, s.messages_sent, s.logged_in);
+            }
+        },
+        Response::Bans(bans) => for ban in bans {
+            let target = match ban.target {
+                BanTarget::Nick(name) => format!("nick {}", name),
+                BanTarget::Ip(range) => format!("ip {}", range),
+            };
+            match ban.expires {
+                Some(expires) => println!("{} until {}", target, expires),
+                None => println!("{}", target),
+            }
+        },
+        Response::Stats(stats) => for (name, value) in stats {
+            println!("{} {}", name, value);
+        },
+        res => return Err(format!("unexpected response: {:?}", res).into()),
+    }
+    Ok(())
+}
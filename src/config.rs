@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::nick::NickPolicy;
+use crate::limit::Rates;
+use crate::storage::Backend;
+
+const IP_RATE_FACTOR: u32 = 4;
+
+/// Server settings, read from a small ini style file:
+///
+/// ```text
+/// # comment
+/// listen = 127.0.0.1:6142
+///
+/// [nick]
+/// max_length = 16
+/// reserved = admin, root
+/// ```
+///
+/// Keys inside a section are looked up as `section.key`. Anything left out keeps its default.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: String,
+    pub history_file: String,
+    /// Where accounts, contact lists, bans and queued messages are kept.
+    pub storage: Backend,
+    pub data_dir: String,
+    /// A ban list from before `storage`, imported once and then renamed.
+    pub bans_file: String,
+    pub retention: Retention,
+    /// Seconds between looking for messages to delete.
+    pub sweep_interval: u64,
+    /// Where security relevant events are logged, left empty they aren't.
+    pub audit_file: String,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    /// Where to serve metrics over HTTP, left empty they aren't served.
+    pub metrics_listen: String,
+    /// Where to serve the admin API, left empty it isn't served. Requests need `admin_token`.
+    pub admin_listen: String,
+    pub admin_token: String,
+    pub nick: NickPolicy,
+    /// Nicknames that can become operators, and the password they need for it.
+    pub operators: HashMap<String, String>,
+    /// Limits for each connection, and for each account across its connections.
+    pub rate_limit: Rates,
+    /// Limits shared by every connection from the same address.
+    pub ip_rate_limit: Rates,
+    /// A `tracing` filter like `info` or `chat_server=debug`, `RUST_LOG` takes precedence.
+    pub log_level: String,
+    pub log_format: LogFormat,
+}
+
+/// How many seconds messages are kept for, 0 keeps them for good.
+/// Disappearing messages go once their own time is up regardless.
+///
+/// Only direct messages have a setting. The server has no channels, so there is no channel
+/// history to keep or expire.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    /// Messages between two users.
+    pub direct: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with the connection and command each event happened in.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogFormat, ()> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: "127.0.0.1:6142".to_string(),
+            history_file: "history.db".to_string(),
+            storage: Backend::File,
+            data_dir: "data".to_string(),
+            bans_file: "bans.db".to_string(),
+            retention: Retention::default(),
+            sweep_interval: 60,
+            audit_file: "audit.log".to_string(),
+            max_connections: 1024,
+            max_connections_per_ip: 16,
+            metrics_listen: String::new(),
+            admin_listen: String::new(),
+            admin_token: String::new(),
+            nick: NickPolicy::default(),
+            operators: HashMap::new(),
+            rate_limit: Rates::default(),
+            ip_rate_limit: Rates::default().times(IP_RATE_FACTOR),
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file at `path`, falling back to the defaults if it doesn't exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(text: &str) -> io::Result<Config> {
+        let values = Values::parse(text)?;
+        let mut config = Config::default();
+
+        values.set("listen", &mut config.listen)?;
+        values.set("history_file", &mut config.history_file)?;
+        values.set("storage", &mut config.storage)?;
+        values.set("data_dir", &mut config.data_dir)?;
+        values.set("bans_file", &mut config.bans_file)?;
+        values.set("retention.direct", &mut config.retention.direct)?;
+        values.set("sweep_interval", &mut config.sweep_interval)?;
+        values.set("audit_file", &mut config.audit_file)?;
+        values.set("max_connections", &mut config.max_connections)?;
+        values.set("max_connections_per_ip", &mut config.max_connections_per_ip)?;
+        values.set("metrics_listen", &mut config.metrics_listen)?;
+        values.set("admin_listen", &mut config.admin_listen)?;
+        values.set("admin_token", &mut config.admin_token)?;
+        if !config.admin_listen.is_empty() && config.admin_token.is_empty() {
+            return Err(invalid("admin_listen is set without an admin_token".to_string()));
+        }
+        values.set("log_level", &mut config.log_level)?;
+        values.set("log_format", &mut config.log_format)?;
+
+        values.set("nick.min_length", &mut config.nick.min_len)?;
+        values.set("nick.max_length", &mut config.nick.max_len)?;
+        values.set("nick.allow_unicode", &mut config.nick.allow_unicode)?;
+        values.set("nick.extra_chars", &mut config.nick.extra_chars)?;
+        if let Some(reserved) = values.list("nick.reserved") {
+            config.nick.reserved.extend(reserved);
+        }
+        config.operators = values.section("operators");
+
+        values.rates("rate_limit", &mut config.rate_limit)?;
+        // Unless told otherwise an address gets a few connections' worth
+        config.ip_rate_limit = config.rate_limit.times(IP_RATE_FACTOR);
+        values.rates("ip_rate_limit", &mut config.ip_rate_limit)?;
+
+        Ok(config)
+    }
+}
+
+struct Values(HashMap<String, String>);
+
+impl Values {
+    fn parse(text: &str) -> io::Result<Values> {
+        let mut values = HashMap::new();
+        let mut section = String::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') { continue; }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| invalid(format!("line {}: expected `key = value`", n + 1)))?;
+            let key = match section.as_str() {
+                "" => key.trim().to_string(),
+                section => format!("{}.{}", section, key.trim()),
+            };
+            values.insert(key, value.trim().to_string());
+        }
+        Ok(Values(values))
+    }
+
+    /// Overwrites `field` if the key is present.
+    fn set<T: FromStr>(&self, key: &str, field: &mut T) -> io::Result<()> {
+        if let Some(value) = self.0.get(key) {
+            *field = value.parse().map_err(|_| invalid(format!("{}: invalid value `{}`", key, value)))?;
+        }
+        Ok(())
+    }
+
+    fn rates(&self, section: &str, rates: &mut Rates) -> io::Result<()> {
+        self.set(&format!("{}.login", section), &mut rates.login)?;
+        self.set(&format!("{}.message", section), &mut rates.message)?;
+        self.set(&format!("{}.search", section), &mut rates.search)?;
+        self.set(&format!("{}.import", section), &mut rates.import)?;
+        self.set(&format!("{}.other", section), &mut rates.other)
+    }
+
+    /// Every key in the section, without the section name in front.
+    fn section(&self, name: &str) -> HashMap<String, String> {
+        let prefix = format!("{}.", name);
+        self.0.iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(&prefix)?.to_string(), value.clone())))
+            .collect()
+    }
+
+    /// Comma separated values, empty entries are dropped.
+    fn list(&self, key: &str) -> Option<Vec<String>> {
+        self.0.get(key).map(|value| value.split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect())
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
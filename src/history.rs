@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::PathBuf;
+
+use crate::json::Value;
+use crate::nick;
+use crate::search::Index;
+use crate::{Serialize, Deserialize, Packet, string_packet, u64_packet, Nickname, Msg, MsgId, Timestamp};
+
+/// A message as it was routed by the server.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub id: MsgId,
+    pub from: Nickname,
+    pub to: Nickname,
+    pub timestamp: Timestamp,
+    pub msg: Msg,
+    /// When a disappearing message is deleted.
+    pub expires: Option<Timestamp>,
+}
+
+impl Record {
+    /// Whether the record is part of the conversation between `a` and `b`, which are given
+    /// as `nick::key`s.
+    pub fn between(&self, a: &str, b: &str) -> bool {
+        let (from, to) = (nick::key(&self.from), nick::key(&self.to));
+        (from == a && to == b) || (from == b && to == a)
+    }
+
+    /// Where the record goes in its conversation. Messages sent in the same millisecond are
+    /// told apart by their ids, so this is also what `Command::History` pages from.
+    pub fn position(&self) -> (Timestamp, MsgId) {
+        (self.timestamp, self.id)
+    }
+
+    /// Puts `new` in place of whichever end of the message is `old_key`.
+    pub fn rename(&mut self, old_key: &str, new: &str) {
+        if nick::key(&self.from) == old_key { self.from = new.to_string(); }
+        if nick::key(&self.to) == old_key { self.to = new.to_string(); }
+    }
+
+    pub fn expired(&self, now: Timestamp) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// The record as one line of a JSON Lines archive. Ids are written as strings, since a
+    /// JSON number can't hold every `u64`.
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("id", self.id.to_string().into()),
+            ("from", self.from.as_str().into()),
+            ("to", self.to.as_str().into()),
+            ("timestamp", self.timestamp.into()),
+            ("msg", self.msg.as_str().into()),
+            ("expires", self.expires.into()),
+        ])
+    }
+
+    pub fn from_json(value: &Value) -> Option<Record> {
+        Some(Record {
+            id: value.get("id")?.as_str()?.parse().ok()?,
+            from: value.get("from")?.as_str()?.to_string(),
+            to: value.get("to")?.as_str()?.to_string(),
+            timestamp: value.get("timestamp")?.as_u64()?,
+            msg: value.get("msg")?.as_str()?.to_string(),
+            expires: match value.get("expires") {
+                None | Some(Value::Null) => None,
+                Some(expires) => Some(expires.as_u64()?),
+            },
+        })
+    }
+}
+
+/// The record as a line of a plain text archive.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {} -> {}: {}", self.timestamp, self.from, self.to, self.msg)
+    }
+}
+
+impl Serialize for Record {
+    fn serialize(&self) -> Vec<Packet> {
+        vec![
+            string_packet(&self.from, 0),
+            string_packet(&self.to, 0),
+            u64_packet(self.id, 0),
+            u64_packet(self.timestamp, 0),
+            string_packet(&self.msg, 0),
+            u64_packet(self.expires.unwrap_or(0), 0),
+        ]
+    }
+}
+
+impl Deserialize<Record> for [Packet] {
+    fn deserialize(&self) -> Option<Record> {
+        // Left out by records written before messages could expire
+        let expires: u64 = match self.get(5) {
+            Some(packet) => packet.deserialize()?,
+            None => 0,
+        };
+        Some(Record {
+            from: self.first()?.deserialize()?,
+            to: self.get(1)?.deserialize()?,
+            id: self.get(2)?.deserialize()?,
+            timestamp: self.get(3)?.deserialize()?,
+            msg: self.get(4)?.deserialize()?,
+            expires: if expires == 0 { None } else { Some(expires) },
+        })
+    }
+}
+
+/// Number of packets a serialized `Record` takes up.
+pub const RECORD_PACKETS: usize = 6;
+
+/// Message log. Every record is written to the file as a frame of packets, the same way they
+/// go over the wire, and the whole log is kept in memory for lookups. New records are
+/// appended, anything that changes or removes records writes the whole file over.
+#[derive(Default)]
+pub struct History {
+    // `None` for a log that is only kept in memory
+    file: Option<(PathBuf, File)>,
+    records: Vec<Record>,
+    ids: HashMap<MsgId, usize>,
+    index: Index,
+}
+
+impl History {
+    /// Opens the log at `path`, `History::default()` is one that is only kept in memory.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<History> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        let mut reader = BufReader::new(&file);
+        let mut records = Vec::new();
+        while let Some(frame) = Packet::read_frame(&mut reader)? {
+            match frame[..].deserialize() {
+                Some(record) => records.push(record),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt history record")),
+            }
+        }
+        let mut history = History { file: Some((path, file)), ..History::default() };
+        for record in records {
+            history.insert(record);
+        }
+        Ok(history)
+    }
+
+    /// Looks up a message by its id.
+    pub fn get(&self, id: MsgId) -> Option<&Record> {
+        self.ids.get(&id).map(|&i| &self.records[i])
+    }
+
+    /// Writes the record to the log. A record with an id that is already stored is not written
+    /// again, so clients can retry a message without it showing up twice.
+    pub fn append(&mut self, record: Record) -> io::Result<()> {
+        if self.ids.contains_key(&record.id) { return Ok(()); }
+        if let Some((_, file)) = &mut self.file {
+            file.write_all(&frame(&record))?;
+        }
+        self.insert(record);
+        Ok(())
+    }
+
+    /// Removes every record `keep` returns false for, and returns how many that were.
+    pub fn retain<F: FnMut(&Record) -> bool>(&mut self, mut keep: F) -> io::Result<usize> {
+        let before = self.records.len();
+        self.rebuild(|records| records.retain(|record| keep(record)));
+        let removed = before - self.records.len();
+        if removed > 0 { self.rewrite()?; }
+        Ok(removed)
+    }
+
+    /// Replaces the text of a message, returns whether there was one with that id.
+    pub fn edit(&mut self, id: MsgId, msg: &str) -> io::Result<bool> {
+        let Some(&i) = self.ids.get(&id) else { return Ok(false) };
+        self.rebuild(|records| records[i].msg = msg.to_string());
+        self.rewrite()?;
+        Ok(true)
+    }
+
+    /// Puts `new` in place of the name of `old_key` on every message they sent or received.
+    pub fn rename(&mut self, old_key: &str, new: &str) -> io::Result<()> {
+        if self.involving(old_key).is_empty() { return Ok(()); }
+        self.rebuild(|records| records.iter_mut().for_each(|record| record.rename(old_key, new)));
+        self.rewrite()
+    }
+
+    /// Removes a message, returns whether there was one with that id.
+    pub fn remove(&mut self, id: MsgId) -> io::Result<bool> {
+        Ok(self.retain(|record| record.id != id)? > 0)
+    }
+
+    /// Every message `key` sent or received, oldest first.
+    pub fn involving(&self, key: &str) -> Vec<Record> {
+        self.records.iter()
+            .filter(|r| nick::key(&r.from) == key || nick::key(&r.to) == key)
+            .cloned()
+            .collect()
+    }
+
+    /// The newest `limit` messages between `a` and `b` that come before the `Record::position`
+    /// `before`, oldest first.
+    pub fn conversation(&self, a: &str, b: &str, before: Option<(Timestamp, MsgId)>, limit: usize) -> Vec<Record> {
+        let mut records = self.between(a, b);
+        records.retain(|r| before.is_none_or(|before| r.position() < before));
+        records.split_off(records.len().saturating_sub(limit))
+    }
+
+    /// The messages `key` sent or received with every word of `query` in them, newest first.
+    /// Only those exchanged with `peer` and sent since `since` if given.
+    pub fn search(&self, key: &str, query: &str, peer: Option<&str>, since: Option<Timestamp>) -> Vec<Record> {
+        let peer = peer.map(nick::key);
+        self.index.find(query).into_iter()
+            .rev()
+            .map(|i| &self.records[i])
+            .filter(|r| since.is_none_or(|since| r.timestamp >= since))
+            .filter(|r| match &peer {
+                Some(peer) => r.between(key, peer),
+                None => nick::key(&r.from) == key || nick::key(&r.to) == key,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The first `limit` messages between `a` and `b` that come after the `Record::position`
+    /// `after`, oldest first.
+    pub fn following(&self, a: &str, b: &str, after: (Timestamp, MsgId), limit: usize) -> Vec<Record> {
+        let mut records = self.between(a, b);
+        records.retain(|r| r.position() > after);
+        records.truncate(limit);
+        records
+    }
+
+    // The conversation between `a` and `b` in order. Records are mostly appended in order
+    // already, but imports and clocks that are off can put them anywhere.
+    fn between(&self, a: &str, b: &str) -> Vec<Record> {
+        let (a, b) = (nick::key(a), nick::key(b));
+        let mut records: Vec<Record> = self.records.iter()
+            .filter(|r| r.between(&a, &b))
+            .cloned()
+            .collect();
+        records.sort_by_key(Record::position);
+        records
+    }
+
+    // Changes the records and brings the lookups up to date with them
+    fn rebuild<F: FnOnce(&mut Vec<Record>)>(&mut self, change: F) {
+        let mut records = std::mem::take(&mut self.records);
+        change(&mut records);
+        self.ids.clear();
+        self.index.clear();
+        for record in records {
+            self.insert(record);
+        }
+    }
+
+    // Written to a new file first, so a crash halfway through doesn't lose the old log
+    fn rewrite(&mut self) -> io::Result<()> {
+        if let Some((path, file)) = &mut self.file {
+            let tmp = path.with_extension("tmp");
+            File::create(&tmp)?.write_all(&self.records.iter().flat_map(frame).collect::<Vec<u8>>())?;
+            fs::rename(&tmp, &*path)?;
+            *file = OpenOptions::new().append(true).open(&*path)?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, record: Record) {
+        self.index.add(self.records.len(), &record.msg);
+        self.ids.insert(record.id, self.records.len());
+        self.records.push(record);
+    }
+}
+
+fn frame(record: &Record) -> Vec<u8> {
+    let mut bytes = Packet::to_byte_vec(record.serialize());
+    bytes.extend_from_slice(&[0, 0, 0, 0]);
+    bytes
+}
@@ -0,0 +1,147 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAX_HEAD: usize = 8 * 1024;
+const MAX_BODY: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Names are lowercased.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct Reply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Reply {
+    pub fn text(status: u16, body: String) -> Reply {
+        Reply { status, content_type: "text/plain; version=0.0.4", body }
+    }
+
+    pub fn json(status: u16, body: String) -> Reply {
+        Reply { status, content_type: "application/json", body }
+    }
+}
+
+/// Reads a request, `None` if the connection closed before a whole one arrived. Just enough
+/// HTTP/1.1 for the small local endpoints the server has, one request per connection.
+pub async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut bytes = Vec::new();
+    let mut buffer = [0; 1024];
+    let head_end = loop {
+        if let Some(at) = bytes.windows(4).position(|w| w == b"\r\n\r\n") { break at; }
+        if bytes.len() > MAX_HEAD { return Err(invalid("request head too large")); }
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 { return Ok(None); }
+        bytes.extend_from_slice(&buffer[..n]);
+    };
+
+    let head = String::from_utf8(bytes[..head_end].to_vec()).map_err(|_| invalid("request head is not utf-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut start = lines.next().unwrap_or("").split(' ');
+    let method = start.next().unwrap_or("").to_string();
+    let path = start.next().ok_or_else(|| invalid("missing path"))?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let length = headers.iter()
+        .find(|(name, _)| name == "content-length")
+        .map(|(_, value)| value.parse::<usize>().map_err(|_| invalid("bad content-length")))
+        .transpose()?
+        .unwrap_or(0);
+    if length > MAX_BODY { return Err(invalid("request body too large")); }
+    let mut body = bytes.split_off(head_end + 4);
+    if body.len() < length {
+        let mut rest = vec![0; length - body.len()];
+        reader.read_exact(&mut rest).await?;
+        body.extend_from_slice(&rest);
+    }
+    body.truncate(length);
+
+    Ok(Some(Request { method, path, headers, body }))
+}
+
+/// Decodes the `%XX` escapes in a piece of a path, `None` if they are broken or don't make
+/// utf-8.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            if !hex.bytes().all(|c| c.is_ascii_hexdigit()) { return None; }
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+pub async fn write_reply<W: AsyncWrite + Unpin>(writer: &mut W, reply: &Reply) -> io::Result<()> {
+    let head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                       reply.status, reason(reply.status), reply.content_type, reply.body.len());
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(reply.body.as_bytes()).await?;
+    writer.flush().await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _   => "",
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_a_request() {
+        let mut bytes = &b"POST /kick HTTP/1.1\r\nAuthorization: Bearer t\r\nContent-Length: 14\r\n\r\n{\"nick\":\"bob\"}"[..];
+        let req = read_request(&mut bytes).await.unwrap().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/kick"));
+        assert_eq!(req.header("authorization"), Some("Bearer t"));
+        assert_eq!(req.body, b"{\"nick\":\"bob\"}");
+    }
+
+    #[tokio::test]
+    async fn closed_connections_and_bad_heads_are_told_apart() {
+        assert!(read_request(&mut &b"GET / HTTP/1.1\r\n"[..]).await.unwrap().is_none());
+        assert!(read_request(&mut &b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n"[..]).await.is_err());
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("b%C3%B6b").as_deref(), Some("böb"));
+        assert_eq!(percent_decode("bob").as_deref(), Some("bob"));
+        assert_eq!(percent_decode("b%G0"), None);
+        assert_eq!(percent_decode("b%C3"), None);
+        assert_eq!(percent_decode("b%2"), None);
+    }
+}
@@ -0,0 +1,327 @@
+use std::fmt;
+
+// Arrays and objects nested deeper than this don't parse, the parser recurses for every level
+// and would run out of stack long before running out of input
+const MAX_DEPTH: usize = 64;
+
+/// A parsed JSON document. Objects keep their keys in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn parse(text: &str) -> Option<Value> {
+        let mut parser = Parser { chars: text.chars().collect(), at: 0, depth: 0 };
+        let value = parser.value()?;
+        parser.space();
+        if parser.at == parser.chars.len() { Some(value) } else { None }
+    }
+
+    /// Builds an object from `(key, value)` pairs.
+    pub fn object<'a, I: IntoIterator<Item = (&'a str, Value)>>(fields: I) -> Value {
+        Value::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Whole, non-negative numbers only.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 && *n <= u64::MAX as f64 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value { Value::String(s.to_string()) }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value { Value::String(s) }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Value { Value::Number(n as f64) }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value { Value::Bool(b) }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value { value.map_or(Value::Null, Into::into) }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write_str(f, s),
+            Value::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Value::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser {
+    chars: Vec<char>,
+    at: usize,
+    // Arrays and objects we are inside of
+    depth: usize,
+}
+
+impl Parser {
+    fn space(&mut self) {
+        while self.chars.get(self.at).is_some_and(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) { self.at += 1; }
+    }
+
+    fn eat(&mut self, c: char) -> Option<()> {
+        self.space();
+        if self.chars.get(self.at) == Some(&c) {
+            self.at += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Option<Value> {
+        for c in word.chars() {
+            if self.chars.get(self.at) != Some(&c) { return None; }
+            self.at += 1;
+        }
+        Some(value)
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.space();
+        match self.chars.get(self.at)? {
+            'n' => self.literal("null", Value::Null),
+            't' => self.literal("true", Value::Bool(true)),
+            'f' => self.literal("false", Value::Bool(false)),
+            '"' => self.string().map(Value::String),
+            c @ ('[' | '{') => {
+                if self.depth == MAX_DEPTH { return None; }
+                let array = *c == '[';
+                self.at += 1;
+                self.depth += 1;
+                let value = if array { self.array() } else { self.object() };
+                self.depth -= 1;
+                value
+            },
+            _ => self.number(),
+        }
+    }
+
+    // The rest of an array, after the `[`
+    fn array(&mut self) -> Option<Value> {
+        let mut values = Vec::new();
+        if self.eat(']').is_some() { return Some(Value::Array(values)); }
+        loop {
+            values.push(self.value()?);
+            if self.eat(']').is_some() { return Some(Value::Array(values)); }
+            self.eat(',')?;
+        }
+    }
+
+    // The rest of an object, after the `{`
+    fn object(&mut self) -> Option<Value> {
+        let mut fields = Vec::new();
+        if self.eat('}').is_some() { return Some(Value::Object(fields)); }
+        loop {
+            self.space();
+            let key = self.string()?;
+            self.eat(':')?;
+            fields.push((key, self.value()?));
+            if self.eat('}').is_some() { return Some(Value::Object(fields)); }
+            self.eat(',')?;
+        }
+    }
+
+    // Only what the grammar allows: no plus sign, leading zeros or digitless fractions,
+    // which `f64::from_str` would take
+    fn number(&mut self) -> Option<Value> {
+        let start = self.at;
+        self.skip('-');
+        if !self.skip('0') && self.digits() == 0 { return None; }
+        if self.skip('.') && self.digits() == 0 { return None; }
+        if self.skip('e') || self.skip('E') {
+            let _ = self.skip('+') || self.skip('-');
+            if self.digits() == 0 { return None; }
+        }
+        let text: String = self.chars[start..self.at].iter().collect();
+        text.parse().ok().map(Value::Number)
+    }
+
+    // Moves past `c` if it is next, unlike `eat` whitespace in front of it doesn't count
+    fn skip(&mut self, c: char) -> bool {
+        let next = self.chars.get(self.at) == Some(&c);
+        if next { self.at += 1; }
+        next
+    }
+
+    // Moves past a run of digits, returns how many there were
+    fn digits(&mut self) -> usize {
+        let start = self.at;
+        while self.chars.get(self.at).is_some_and(char::is_ascii_digit) { self.at += 1; }
+        self.at - start
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.chars.get(self.at) != Some(&'"') { return None; }
+        self.at += 1;
+        let mut s = String::new();
+        loop {
+            let c = *self.chars.get(self.at)?;
+            self.at += 1;
+            match c {
+                '"' => return Some(s),
+                '\\' => {
+                    let escaped = *self.chars.get(self.at)?;
+                    self.at += 1;
+                    s.push(match escaped {
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'u' => self.unicode()?,
+                        '"' | '\\' | '/' => escaped,
+                        _ => return None,
+                    });
+                },
+                // Control characters have to be escaped
+                c if (c as u32) < 0x20 => return None,
+                c => s.push(c),
+            }
+        }
+    }
+
+    // The four hex digits after `\u`, and the low half after it if this is a surrogate pair
+    fn unicode(&mut self) -> Option<char> {
+        let high = self.hex()?;
+        if (0xd800..0xdc00).contains(&high) {
+            if self.chars.get(self.at) != Some(&'\\') || self.chars.get(self.at + 1) != Some(&'u') { return None; }
+            self.at += 2;
+            let low = self.hex()?;
+            char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low.checked_sub(0xdc00)?))
+        } else {
+            char::from_u32(high)
+        }
+    }
+
+    fn hex(&mut self) -> Option<u32> {
+        let digits: String = self.chars.get(self.at..self.at + 4)?.iter().collect();
+        self.at += 4;
+        // `from_str_radix` would let a sign through
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) { return None; }
+        u32::from_str_radix(&digits, 16).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_documents() {
+        let value = Value::parse(r#" {"nick": "bob", "seconds": 60, "tags": [true, null, -1.5e2]} "#).unwrap();
+        assert_eq!(value.get("nick").and_then(Value::as_str), Some("bob"));
+        assert_eq!(value.get("seconds").and_then(Value::as_u64), Some(60));
+        assert_eq!(value.get("tags").and_then(Value::as_array),
+                   Some(&[Value::Bool(true), Value::Null, Value::Number(-150.0)][..]));
+    }
+
+    #[test]
+    fn numbers_follow_the_grammar() {
+        for good in ["0", "-0", "10", "1.5", "1e3", "1E+3", "2.5e-3"] {
+            assert!(Value::parse(good).is_some(), "{}", good);
+        }
+        for bad in ["+1", "01", "1.", ".5", "1e", "-", "1e+", "--1", "0x10"] {
+            assert_eq!(Value::parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn strings_follow_the_grammar() {
+        assert_eq!(Value::parse(r#""a\"\\\/\né😀""#), Some(Value::from("a\"\\/\né😀")));
+        for bad in [r#""\u+123""#, r#""\u12""#, r#""\x""#, "\"a\nb\"", r#""\udc00""#, r#""open"#] {
+            assert_eq!(Value::parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Value::parse(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(Value::parse(&nested(MAX_DEPTH + 1)), None);
+        assert_eq!(Value::parse(&"{\"a\":".repeat(MAX_DEPTH + 1)), None);
+        // Far past the limit it fails the same way instead of overflowing the stack
+        assert_eq!(Value::parse(&"[".repeat(200_000)), None);
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let value = Value::object([("text", "tab\there \"quoted\" \u{1}".into()), ("n", 3u64.into())]);
+        assert_eq!(Value::parse(&value.to_string()), Some(value));
+    }
+}
@@ -1,57 +1,245 @@
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{self, Read};
+
+pub mod audit;
+pub mod ban;
+pub mod config;
+pub mod history;
+pub mod http;
+pub mod json;
+pub mod limit;
+pub mod metrics;
+pub mod nick;
+pub mod password;
+pub mod presence;
+pub mod search;
+pub mod storage;
 // use crate::request::Command;
 
 type Msg = String;
 type Nickname = String;
+type MsgId = u64;
+type Timestamp = u64;
 
 pub mod request {
     use std::net::SocketAddr;
-    use crate::{Serialize, Deserialize, Packet, to_packet, Nickname, Msg};
+    use crate::{Serialize, Deserialize, Packet, to_packet, string_packet, u64_packet, Nickname, Msg, MsgId, Timestamp};
+    use crate::search::{SearchMode, Page};
+    use crate::presence::Presence;
+    use crate::ban::BanTarget;
+    use crate::history::{Record, RECORD_PACKETS};
 
     #[derive(Debug)]
     pub enum Command {
-        Login(Nickname, SocketAddr),
+        /// Nickname, the address we take messages on, and the password of the account. The
+        /// first login with a password sets it, after that it has to match.
+        Login(Nickname, SocketAddr, Option<Msg>),
         Logout,
-        Search(Nickname),
+        Search(SearchMode, Nickname, Page),
         Exit,
-        Message(Nickname, Msg),
+        /// Recipient, client generated id, the text, and for disappearing messages how many
+        /// seconds they are kept. Resending with the same id is a retry.
+        Message(Nickname, MsgId, Msg, Option<u64>),
         Show,
+        /// Pages backwards through the conversation with `peer`, `limit` messages at a time.
+        /// `before` is the timestamp and id of the oldest message of the last page, since
+        /// several messages can share a timestamp.
+        History { peer: Nickname, before: Option<(Timestamp, MsgId)>, limit: u32 },
+        /// Change the nickname of the current session.
+        Nick(Nickname),
+        /// Set our presence and status text, an empty text clears it.
+        Status(Presence, Msg),
+        /// Get told whenever this user logs in, logs out or changes status.
+        AddContact(Nickname),
+        RemoveContact(Nickname),
+        Contacts,
+        /// Blocked users can't message us, find us or see our presence.
+        Block(Nickname),
+        Unblock(Nickname),
+        Blocked,
+        /// Become an operator with the password configured for our nickname.
+        Oper(Msg),
+        /// Operator only, makes another online user an operator for the rest of their session.
+        Grant(Nickname),
+        /// Operator only, disconnects the user.
+        Kick(Nickname),
+        /// Operator only, for the given number of seconds or for good.
+        Ban(BanTarget, Option<u64>),
+        Unban(BanTarget),
+        Bans,
+        /// Operator only, keeps the user from sending messages for the given number of seconds or
+        /// until they are unmuted.
+        Mute(Nickname, Option<u64>),
+        Unmute(Nickname),
+        /// Operator only, how many connections are open.
+        Connections,
+        /// Makes this an admin connection, which can do everything operators can without
+        /// logging in. Needs the server's admin token.
+        Admin(Msg),
+        /// Admin only, everyone logged in along with stats for their session.
+        Sessions,
+        /// Admin only, pushes the text to everyone online.
+        Broadcast(Msg),
+        /// Admin only, the server's counters.
+        Stats,
+        /// Every message the user sent or received, answered with `Response::History`. Users
+        /// can export their own, operators anyone's.
+        Export(Nickname),
+        /// Admin only, adds messages to the history. Ones already there are left alone.
+        Import(Vec<Record>),
+        /// Messages we sent or received with every word of `query` in them, newest first,
+        /// answered with `Response::Matches`. Only those exchanged with `peer` and sent since
+        /// `since` if given.
+        SearchMessages { query: Msg, peer: Option<Nickname>, since: Option<Timestamp>, page: Page },
+        /// Replaces the text of a message we sent. Operators can edit anyone's.
+        Edit(MsgId, Msg),
+        /// Deletes a message we sent. Operators can delete anyone's.
+        Delete(MsgId),
+        /// Reacts to a message we sent or received with an emoji.
+        React { message_id: MsgId, emoji: Msg },
+        Unreact { message_id: MsgId, emoji: Msg },
+    }
+
+    impl Command {
+        /// What kind of command it is, for logging without the contents.
+        pub fn name(&self) -> &'static str {
+            match self {
+                Command::Login(..)        => "login",
+                Command::Logout           => "logout",
+                Command::Search(..)       => "search",
+                Command::Exit             => "exit",
+                Command::Message(..)      => "message",
+                Command::Show             => "show",
+                Command::History { .. }   => "history",
+                Command::Nick(..)         => "nick",
+                Command::Status(..)       => "status",
+                Command::AddContact(..)   => "add_contact",
+                Command::RemoveContact(..) => "remove_contact",
+                Command::Contacts         => "contacts",
+                Command::Block(..)        => "block",
+                Command::Unblock(..)      => "unblock",
+                Command::Blocked          => "blocked",
+                Command::Oper(..)         => "oper",
+                Command::Grant(..)        => "grant",
+                Command::Kick(..)         => "kick",
+                Command::Ban(..)          => "ban",
+                Command::Unban(..)        => "unban",
+                Command::Bans             => "bans",
+                Command::Mute(..)         => "mute",
+                Command::Unmute(..)       => "unmute",
+                Command::Connections      => "connections",
+                Command::Admin(..)        => "admin",
+                Command::Sessions         => "sessions",
+                Command::Broadcast(..)    => "broadcast",
+                Command::Stats            => "stats",
+                Command::Export(..)       => "export",
+                Command::Import(..)       => "import",
+                Command::SearchMessages { .. } => "search_messages",
+                Command::Edit(..)         => "edit",
+                Command::Delete(..)       => "delete",
+                Command::React { .. }     => "react",
+                Command::Unreact { .. }   => "unreact",
+            }
+        }
     }
 
     impl Serialize for Command {
         fn serialize(&self) -> Vec<Packet> {
             match &self {
-                Command::Login(name, addr) => {
-                    let mut packet = to_packet(name.bytes().len(), 0);
+                Command::Login(name, addr, password) => {
+                    let mut packet = to_packet(name.len(), 0);
                     for byte in name.bytes() {
                         packet.data.push(byte);
                     }
                     // packet.data.append(&mut addr.serialize());
-                    vec![packet, addr.serialize().pop().unwrap()]
+                    let mut packets = vec![packet, addr.serialize().pop().unwrap()];
+                    if let Some(password) = password {
+                        packets.push(string_packet(password, 0));
+                    }
+                    packets
                 },
                 Command::Logout => vec![to_packet(0, 1)],
-                Command::Search(string) => {
-                    let mut packet = to_packet(string.bytes().len(), 2);
+                Command::Search(mode, string, page) => {
+                    let mut packet = to_packet(string.len(), 2);
                     for byte in string.bytes() {
                         packet.data.push(byte);
                     }
-                    vec![packet]
+                    let mut mode_packet = to_packet(1, 2);
+                    mode_packet.data.push(mode.code());
+                    vec![packet, mode_packet, u64_packet(page.offset as u64, 2), u64_packet(page.limit as u64, 2)]
                 },
                 Command::Exit => vec![to_packet(0, 3)],
-                Command::Message(name, msg) => {
-                    let mut name_packet = to_packet(name.bytes().len(), 4);
-                    for byte in name.bytes() {
-                        name_packet.data.push(byte);
-                    }
-
-                    let mut msg_packet = to_packet(msg.bytes().len(), 4);
-                    for byte in msg.bytes() {
-                        msg_packet.data.push(byte);
+                Command::Message(name, id, msg, lifetime) => {
+                    let mut packets = vec![string_packet(name, 4), u64_packet(*id, 4), string_packet(msg, 4)];
+                    if let Some(lifetime) = lifetime {
+                        packets.push(u64_packet(*lifetime, 4));
                     }
-
-                    vec![name_packet, msg_packet]
+                    packets
                 },
                 Command::Show => vec![to_packet(0, 5)],
+                Command::History { peer, before, limit } => {
+                    let mut packets = vec![string_packet(peer, 6), u64_packet(*limit as u64, 6)];
+                    if let Some((timestamp, id)) = before {
+                        packets.push(u64_packet(*timestamp, 6));
+                        packets.push(u64_packet(*id, 6));
+                    }
+                    packets
+                },
+                Command::Nick(name) => vec![string_packet(name, 7)],
+                Command::Status(presence, status) => {
+                    let mut presence_packet = to_packet(1, 8);
+                    presence_packet.data.push(presence.code());
+                    vec![presence_packet, string_packet(status, 8)]
+                },
+                Command::AddContact(name) => vec![string_packet(name, 9)],
+                Command::RemoveContact(name) => vec![string_packet(name, 10)],
+                Command::Contacts => vec![to_packet(0, 11)],
+                Command::Block(name) => vec![string_packet(name, 12)],
+                Command::Unblock(name) => vec![string_packet(name, 13)],
+                Command::Blocked => vec![to_packet(0, 14)],
+                Command::Oper(password) => vec![string_packet(password, 15)],
+                Command::Grant(name) => vec![string_packet(name, 16)],
+                Command::Kick(name) => vec![string_packet(name, 17)],
+                Command::Ban(target, duration) => {
+                    let mut packets = vec![to_packet(0, 18)];
+                    packets.append(&mut target.serialize());
+                    packets.push(u64_packet(duration.unwrap_or(0), 18));
+                    packets
+                },
+                Command::Unban(target) => {
+                    let mut packets = vec![to_packet(0, 19)];
+                    packets.append(&mut target.serialize());
+                    packets
+                },
+                Command::Bans => vec![to_packet(0, 20)],
+                Command::Mute(name, duration) => vec![string_packet(name, 21), u64_packet(duration.unwrap_or(0), 21)],
+                Command::Unmute(name) => vec![string_packet(name, 22)],
+                Command::Connections => vec![to_packet(0, 23)],
+                Command::Admin(token) => vec![string_packet(token, 24)],
+                Command::Sessions => vec![to_packet(0, 25)],
+                Command::Broadcast(msg) => vec![string_packet(msg, 26)],
+                Command::Stats => vec![to_packet(0, 27)],
+                Command::Export(name) => vec![string_packet(name, 28)],
+                Command::Import(records) => {
+                    let mut packets = vec![to_packet(0, 29)];
+                    for record in records {
+                        packets.append(&mut record.serialize());
+                    }
+                    packets
+                },
+                // An empty peer and a zero `since` leave them out
+                Command::SearchMessages { query, peer, since, page } => vec![
+                    string_packet(query, 30),
+                    string_packet(peer.as_deref().unwrap_or(""), 30),
+                    u64_packet(since.unwrap_or(0), 30),
+                    u64_packet(page.offset as u64, 30),
+                    u64_packet(page.limit as u64, 30),
+                ],
+                Command::Edit(id, msg) => vec![u64_packet(*id, 31), string_packet(msg, 31)],
+                Command::Delete(id) => vec![u64_packet(*id, 32)],
+                Command::React { message_id, emoji } => vec![u64_packet(*message_id, 33), string_packet(emoji, 33)],
+                Command::Unreact { message_id, emoji } => vec![u64_packet(*message_id, 34), string_packet(emoji, 34)],
             }
         }
     }
@@ -66,20 +254,114 @@ pub mod request {
                 0 => {
                     let name = String::from_utf8(packet.data.to_vec()).ok()?;
                     let addr = packets.next()?.deserialize()?;
-                    Some(Command::Login(name, addr))
+                    // Clients from before passwords leave it out, and are told they need one
+                    let password = match packets.next() {
+                        Some(packet) => Some(packet.deserialize()?),
+                        None => None,
+                    };
+                    Some(Command::Login(name, addr, password))
                 },
                 1 => Some(Command::Logout),
                 2 => {
                     let name = String::from_utf8(packet.data.to_vec()).ok()?;
-                    Some(Command::Search(name))
+                    let mode = SearchMode::from_code(*packets.next()?.data.first()?)?;
+                    let offset: u64 = packets.next()?.deserialize()?;
+                    let limit: u64 = packets.next()?.deserialize()?;
+                    Some(Command::Search(mode, name, Page { offset: offset as u32, limit: limit as u32 }))
                 },
                 3 => Some(Command::Exit),
                 4 => {
-                    let name = String::from_utf8(packet.data.to_vec()).ok()?;
-                    let msg = String::from_utf8(packets.next()?.data.to_vec()).ok()?;
-                    Some(Command::Message(name, msg))
+                    let name = packet.deserialize()?;
+                    let id = packets.next()?.deserialize()?;
+                    let msg = packets.next()?.deserialize()?;
+                    let lifetime = match packets.next() {
+                        Some(packet) => Some(packet.deserialize()?),
+                        None => None,
+                    };
+                    Some(Command::Message(name, id, msg, lifetime))
                 },
                 5 => Some(Command::Show),
+                6 => {
+                    let peer = packet.deserialize()?;
+                    let limit: u64 = packets.next()?.deserialize()?;
+                    // Clients from before ids were sent along page by timestamp alone, which an
+                    // id of 0 comes down to
+                    let before = match packets.next() {
+                        Some(packet) => Some((packet.deserialize()?, match packets.next() {
+                            Some(packet) => packet.deserialize()?,
+                            None => 0,
+                        })),
+                        None => None,
+                    };
+                    Some(Command::History { peer, before, limit: limit as u32 })
+                },
+                7 => Some(Command::Nick(packet.deserialize()?)),
+                8 => {
+                    let presence = Presence::from_code(*packet.data.first()?)?;
+                    let status = packets.next()?.deserialize()?;
+                    Some(Command::Status(presence, status))
+                },
+                9 => Some(Command::AddContact(packet.deserialize()?)),
+                10 => Some(Command::RemoveContact(packet.deserialize()?)),
+                11 => Some(Command::Contacts),
+                12 => Some(Command::Block(packet.deserialize()?)),
+                13 => Some(Command::Unblock(packet.deserialize()?)),
+                14 => Some(Command::Blocked),
+                15 => Some(Command::Oper(packet.deserialize()?)),
+                16 => Some(Command::Grant(packet.deserialize()?)),
+                17 => Some(Command::Kick(packet.deserialize()?)),
+                18 => {
+                    let target = packets.next()?.deserialize()?;
+                    let duration: u64 = packets.next()?.deserialize()?;
+                    Some(Command::Ban(target, if duration == 0 { None } else { Some(duration) }))
+                },
+                19 => Some(Command::Unban(packets.next()?.deserialize()?)),
+                20 => Some(Command::Bans),
+                21 => {
+                    let name = packet.deserialize()?;
+                    let duration: u64 = packets.next()?.deserialize()?;
+                    Some(Command::Mute(name, if duration == 0 { None } else { Some(duration) }))
+                },
+                22 => Some(Command::Unmute(packet.deserialize()?)),
+                23 => Some(Command::Connections),
+                24 => Some(Command::Admin(packet.deserialize()?)),
+                25 => Some(Command::Sessions),
+                26 => Some(Command::Broadcast(packet.deserialize()?)),
+                27 => Some(Command::Stats),
+                28 => Some(Command::Export(packet.deserialize()?)),
+                29 => self[1..].chunks(RECORD_PACKETS)
+                    .map(|record| record.deserialize())
+                    .collect::<Option<Vec<Record>>>()
+                    .map(Command::Import),
+                30 => {
+                    let query = packet.deserialize()?;
+                    let peer: Nickname = packets.next()?.deserialize()?;
+                    let since: u64 = packets.next()?.deserialize()?;
+                    let offset: u64 = packets.next()?.deserialize()?;
+                    let limit: u64 = packets.next()?.deserialize()?;
+                    Some(Command::SearchMessages {
+                        query,
+                        peer: if peer.is_empty() { None } else { Some(peer) },
+                        since: if since == 0 { None } else { Some(since) },
+                        page: Page { offset: offset as u32, limit: limit as u32 },
+                    })
+                },
+                31 => {
+                    let id = packet.deserialize()?;
+                    let msg = packets.next()?.deserialize()?;
+                    Some(Command::Edit(id, msg))
+                },
+                32 => Some(Command::Delete(packet.deserialize()?)),
+                33 => {
+                    let message_id = packet.deserialize()?;
+                    let emoji = packets.next()?.deserialize()?;
+                    Some(Command::React { message_id, emoji })
+                },
+                34 => {
+                    let message_id = packet.deserialize()?;
+                    let emoji = packets.next()?.deserialize()?;
+                    Some(Command::Unreact { message_id, emoji })
+                },
                 _ => None,
             }
         }
@@ -90,38 +372,196 @@ pub mod request {
 }
 
 pub mod respond {
-    use std::net::SocketAddr;
-    use crate::{Serialize, Deserialize, Packet, to_packet, Nickname, Msg};
+    use std::net::{IpAddr, SocketAddr};
+    use crate::{Serialize, Deserialize, Packet, to_packet, string_packet, u64_packet, Nickname, Msg, MsgId, Timestamp};
+    use crate::history::{Record, RECORD_PACKETS};
+    use crate::error::Error;
+    use crate::presence::Presence;
+    use crate::ban::{Ban, BAN_PACKETS};
+
+    /// A user as they show up in search results.
+    #[derive(Debug, Clone)]
+    pub struct UserInfo {
+        pub name: Nickname,
+        pub addr: SocketAddr,
+        pub presence: Presence,
+        pub status: Msg,
+    }
+
+    /// A logged in user as admins see them.
+    #[derive(Debug, Clone)]
+    pub struct SessionInfo {
+        pub name: Nickname,
+        /// Where the connection comes from.
+        pub peer: SocketAddr,
+        /// Where the client takes messages from other clients.
+        pub addr: SocketAddr,
+        pub presence: Presence,
+        pub operator: bool,
+        pub logged_in: Timestamp,
+        pub commands: u64,
+        pub messages_sent: u64,
+    }
+
+    const SESSION_PACKETS: usize = 7;
+
+    /// A message found by `Command::SearchMessages`, with the messages right before and after
+    /// it in the same conversation.
+    #[derive(Debug, Clone)]
+    pub struct Match {
+        pub record: Record,
+        pub before: Vec<Record>,
+        pub after: Vec<Record>,
+    }
+
+    impl Serialize for Match {
+        fn serialize(&self) -> Vec<Packet> {
+            let mut packets = vec![u64_packet(self.before.len() as u64, 24), u64_packet(self.after.len() as u64, 24)];
+            for record in std::iter::once(&self.record).chain(&self.before).chain(&self.after) {
+                packets.append(&mut record.serialize());
+            }
+            packets
+        }
+    }
+
+    impl Match {
+        // Takes the match off the front of `packets`, and returns what is left after it
+        fn take(packets: &[Packet]) -> Option<(Match, &[Packet])> {
+            let before: u64 = packets.first()?.deserialize()?;
+            let after: u64 = packets.get(1)?.deserialize()?;
+            let end = 2 + (1 + before as usize + after as usize) * RECORD_PACKETS;
+            let mut records = packets.get(2..end)?.chunks(RECORD_PACKETS)
+                .map(|record| record.deserialize())
+                .collect::<Option<Vec<Record>>>()?;
+            let after = records.split_off(1 + before as usize);
+            let before = records.split_off(1);
+            Some((Match { record: records.pop()?, before, after }, &packets[end..]))
+        }
+    }
+
+    impl Serialize for SessionInfo {
+        fn serialize(&self) -> Vec<Packet> {
+            let mut flags = to_packet(2, 22);
+            flags.data.push(self.presence.code());
+            flags.data.push(self.operator as u8);
+            vec![
+                string_packet(&self.name, 22),
+                self.peer.serialize().pop().unwrap(),
+                self.addr.serialize().pop().unwrap(),
+                flags,
+                u64_packet(self.logged_in, 22),
+                u64_packet(self.commands, 22),
+                u64_packet(self.messages_sent, 22),
+            ]
+        }
+    }
+
+    impl Deserialize<SessionInfo> for [Packet] {
+        fn deserialize(&self) -> Option<SessionInfo> {
+            let flags = &self.get(3)?.data;
+            Some(SessionInfo {
+                name: self.first()?.deserialize()?,
+                peer: self.get(1)?.deserialize()?,
+                addr: self.get(2)?.deserialize()?,
+                presence: Presence::from_code(*flags.first()?)?,
+                operator: *flags.get(1)? != 0,
+                logged_in: self.get(4)?.deserialize()?,
+                commands: self.get(5)?.deserialize()?,
+                messages_sent: self.get(6)?.deserialize()?,
+            })
+        }
+    }
 
     #[derive(Debug)]
     pub enum Response {
         Login(Nickname, SocketAddr),
-        Search(Vec<(Nickname, SocketAddr)>),
+        /// One page of matches, and the offset of the next page if there are more.
+        Search(Vec<UserInfo>, Option<u32>),
         Logout,
         Exit,
-        Message(Nickname, Msg, SocketAddr),
+        /// Peer, message id, server timestamp, text, the address of the peer and when the
+        /// message disappears, if it does.
+        Message(Nickname, MsgId, Timestamp, Msg, SocketAddr, Option<Timestamp>),
+        /// Sent back by the receiving client once it has the message.
+        Delivered(MsgId),
+        /// The message could not be routed, with the reason why.
+        Failed(MsgId, Msg),
+        History(Vec<Record>),
+        /// The recipient is offline, the message waits for them on the server.
+        Queued(MsgId),
+        /// Messages queued while we were offline, grouped by sender.
+        Unread(Vec<(Nickname, Vec<Record>)>),
+        Error(Error),
+        /// Our new nickname, the answer to `Command::Nick`.
+        Nick(Nickname),
+        /// Pushed to a user's contacts and those they have talked to when they change
+        /// nickname, old name first.
+        Renamed(Nickname, Nickname),
+        /// Our presence and status text, the answer to `Command::Status`.
+        Status(Presence, Msg),
+        /// Our contact list, with the presence and status text of each contact.
+        Contacts(Vec<(Nickname, Presence, Msg)>),
+        /// Pushed when one of our contacts logs in, logs out or changes status.
+        PresenceChanged(Nickname, Presence, Msg),
+        /// Everyone we have blocked.
+        Blocked(Vec<Nickname>),
+        /// The command went through, for commands that have nothing else to answer with.
+        Ok,
+        /// The bans currently in effect.
+        Bans(Vec<Ban>),
+        /// Pushed right before an operator disconnects us, with the reason.
+        Kicked(Msg),
+        /// How many connections are open in total, and from each address.
+        Connections(u64, Vec<(IpAddr, u64)>),
+        /// Pushed to everyone online, an announcement from whoever runs the server.
+        Broadcast(Msg),
+        Sessions(Vec<SessionInfo>),
+        /// Names and values of the server's counters.
+        Stats(Vec<(Msg, u64)>),
+        /// One page of messages found by `Command::SearchMessages`, and the offset of the next
+        /// page if there are more.
+        Matches(Vec<Match>, Option<u32>),
+        /// Pushed to the other end of the conversation when a message is edited, with its new
+        /// text.
+        MessageEdited(MsgId, Msg),
+        /// Pushed to the other end of the conversation when a message is deleted.
+        MessageDeleted(MsgId),
+        /// Pushed to the other end of the conversation when someone reacts to a message or
+        /// takes a reaction back, with every reaction to it and who reacted with each.
+        Reactions(MsgId, Vec<(Msg, Vec<Nickname>)>),
     }
 
     impl Serialize for Response {
         fn serialize(&self) -> Vec<Packet> {
             match self {
                 Response::Login(name, addr) => {
-                    let mut packet = to_packet(name.bytes().len(), 0);
+                    let mut packet = to_packet(name.len(), 0);
                     for byte in name.bytes() {
                         packet.data.push(byte);
                     }
                     vec![packet, addr.serialize().pop().unwrap()]
                 },
-                Response::Search(users) => users.iter()
-                    .flat_map(|(name, addr)| {
-                        let name: Vec<u8> = name.bytes().collect();
-                        let mut addr = addr.serialize();
-                        let name_packet = Packet { amount: name.len() as u32 + 1, data_type: 1, data: name};
-                        let mut data = vec![name_packet];
-                        data.append(&mut addr);
-                        data
-                    })
-                    .collect(),
+                Response::Search(users, next) => {
+                    // The header holds the next offset, and keeps an empty page from looking like no response
+                    let header = match next {
+                        Some(next) => u64_packet(*next as u64, 1),
+                        None => to_packet(0, 1),
+                    };
+                    let users = users.iter()
+                        .flat_map(|user| {
+                            let name: Vec<u8> = user.name.bytes().collect();
+                            let mut addr = user.addr.serialize();
+                            let name_packet = Packet { amount: name.len() as u32 + 1, data_type: 1, data: name};
+                            let mut presence_packet = to_packet(1, 1);
+                            presence_packet.data.push(user.presence.code());
+                            let mut data = vec![name_packet];
+                            data.append(&mut addr);
+                            data.push(presence_packet);
+                            data.push(string_packet(&user.status, 1));
+                            data
+                        });
+                    std::iter::once(header).chain(users).collect()
+                },
                 Response::Logout => vec![Packet {
                     amount: 1,
                     data_type: 2,
@@ -132,19 +572,100 @@ pub mod respond {
                     data_type: 3,
                     data: Vec::new(),
                 }],
-                Response::Message(name, msg, addr) => {
-                    let mut name_packet = to_packet(name.bytes().len(), 4);
-                    for byte in name.bytes() {
-                        name_packet.data.push(byte);
+                Response::Message(name, id, timestamp, msg, addr, expires) => {
+                    let mut packets = vec![
+                        string_packet(name, 4),
+                        u64_packet(*id, 4),
+                        u64_packet(*timestamp, 4),
+                        string_packet(msg, 4),
+                        addr.serialize().pop().unwrap(),
+                    ];
+                    if let Some(expires) = expires {
+                        packets.push(u64_packet(*expires, 4));
                     }
-
-                    let mut msg_packet = to_packet(msg.bytes().len(), 4);
-                    for byte in msg.bytes() {
-                        msg_packet.data.push(byte);
+                    packets
+                },
+                Response::Delivered(id) => vec![u64_packet(*id, 5)],
+                Response::Failed(id, reason) => vec![u64_packet(*id, 6), string_packet(reason, 6)],
+                Response::History(records) => {
+                    // The header keeps an empty page from looking like no response
+                    let mut packets = vec![to_packet(0, 7)];
+                    for record in records {
+                        packets.append(&mut record.serialize());
                     }
-
-                    vec![name_packet, msg_packet, addr.serialize().pop().unwrap()]
-                }
+                    packets
+                },
+                Response::Queued(id) => vec![u64_packet(*id, 8)],
+                Response::Unread(senders) => {
+                    let mut packets = vec![to_packet(0, 9)];
+                    for (name, records) in senders {
+                        packets.push(string_packet(name, 9));
+                        packets.push(u64_packet(records.len() as u64, 9));
+                        for record in records {
+                            packets.append(&mut record.serialize());
+                        }
+                    }
+                    packets
+                },
+                Response::Error(error) => {
+                    let mut packets = vec![to_packet(0, 10)];
+                    packets.append(&mut error.serialize());
+                    packets
+                },
+                Response::Nick(name) => vec![string_packet(name, 11)],
+                Response::Renamed(old, new) => vec![string_packet(old, 12), string_packet(new, 12)],
+                Response::Status(presence, status) => {
+                    let mut presence_packet = to_packet(1, 13);
+                    presence_packet.data.push(presence.code());
+                    vec![presence_packet, string_packet(status, 13)]
+                },
+                Response::Contacts(contacts) => {
+                    let mut packets = vec![to_packet(0, 14)];
+                    for (name, presence, status) in contacts {
+                        packets.append(&mut presence_packets(name, *presence, status, 14));
+                    }
+                    packets
+                },
+                Response::PresenceChanged(name, presence, status) => presence_packets(name, *presence, status, 15),
+                Response::Blocked(names) => std::iter::once(to_packet(0, 16))
+                    .chain(names.iter().map(|name| string_packet(name, 16)))
+                    .collect(),
+                Response::Ok => vec![to_packet(0, 17)],
+                Response::Bans(bans) => std::iter::once(to_packet(0, 18))
+                    .chain(bans.iter().flat_map(|ban| ban.serialize()))
+                    .collect(),
+                Response::Kicked(reason) => vec![string_packet(reason, 19)],
+                Response::Connections(total, addrs) => std::iter::once(u64_packet(*total, 20))
+                    .chain(addrs.iter().flat_map(|(ip, n)| vec![string_packet(&ip.to_string(), 20), u64_packet(*n, 20)]))
+                    .collect(),
+                Response::Broadcast(msg) => vec![string_packet(msg, 21)],
+                Response::Sessions(sessions) => std::iter::once(to_packet(0, 22))
+                    .chain(sessions.iter().flat_map(|session| session.serialize()))
+                    .collect(),
+                Response::Stats(stats) => std::iter::once(to_packet(0, 23))
+                    .chain(stats.iter().flat_map(|(name, value)| vec![string_packet(name, 23), u64_packet(*value, 23)]))
+                    .collect(),
+                Response::Matches(matches, next) => {
+                    // Same header as `Response::Search`
+                    let header = match next {
+                        Some(next) => u64_packet(*next as u64, 24),
+                        None => to_packet(0, 24),
+                    };
+                    std::iter::once(header)
+                        .chain(matches.iter().flat_map(|found| found.serialize()))
+                        .collect()
+                },
+                Response::MessageEdited(id, msg) => vec![u64_packet(*id, 25), string_packet(msg, 25)],
+                Response::MessageDeleted(id) => vec![u64_packet(*id, 26)],
+                Response::Reactions(id, reactions) => {
+                    let mut packets = vec![u64_packet(*id, 27)];
+                    for (emoji, names) in reactions {
+                        packets.push(string_packet(emoji, 27));
+                        packets.push(u64_packet(names.len() as u64, 27));
+                        packets.extend(names.iter().map(|name| string_packet(name, 27)));
+                    }
+                    packets
+                },
             }
         }
     }
@@ -160,7 +681,7 @@ pub mod respond {
 
     impl Deserialize<Response> for Vec<Packet> {
         fn deserialize(&self) -> Option<Response> {
-            let packet = self.get(0)?;
+            let packet = self.first()?;
             match packet.data_type {
                 0 => {
                     let mut packets = self.iter();
@@ -172,25 +693,294 @@ pub mod respond {
                 1 => {
                     let mut users = Vec::with_capacity(self.len());
                     let mut packets = self.iter();
+                    let next: Option<u64> = packets.next()?.deserialize();
+                    let next = next.map(|next| next as u32);
                     while let Some(user) = packets.next() {
                         let name = String::from_utf8(user.data.to_vec()).ok()?;
                         let addr = packets.next()?.deserialize()?;
-                        users.push((name, addr));
+                        let presence = Presence::from_code(*packets.next()?.data.first()?)?;
+                        let status = packets.next()?.deserialize()?;
+                        users.push(UserInfo { name, addr, presence, status });
                     }
-                    Some(Response::Search(users))
+                    Some(Response::Search(users, next))
                 },
                 2 => Some(Response::Logout),
                 3 => Some(Response::Exit),
                 4 => {
-                    let name = String::from_utf8(self.get(0)?.data.to_vec()).ok()?;
-                    let msg = String::from_utf8(self.get(1)?.data.to_vec()).ok()?;
-                    let addr = self.get(2)?.deserialize()?;
-                    Some(Response::Message(name, msg, addr))
-                }
+                    let name = self.first()?.deserialize()?;
+                    let id = self.get(1)?.deserialize()?;
+                    let timestamp = self.get(2)?.deserialize()?;
+                    let msg = self.get(3)?.deserialize()?;
+                    let addr = self.get(4)?.deserialize()?;
+                    let expires = match self.get(5) {
+                        Some(packet) => Some(packet.deserialize()?),
+                        None => None,
+                    };
+                    Some(Response::Message(name, id, timestamp, msg, addr, expires))
+                },
+                5 => Some(Response::Delivered(self.first()?.deserialize()?)),
+                6 => {
+                    let id = self.first()?.deserialize()?;
+                    let reason = self.get(1)?.deserialize()?;
+                    Some(Response::Failed(id, reason))
+                },
+                7 => self[1..].chunks(RECORD_PACKETS)
+                    .map(|record| record.deserialize())
+                    .collect::<Option<Vec<Record>>>()
+                    .map(Response::History),
+                8 => Some(Response::Queued(self.first()?.deserialize()?)),
+                9 => {
+                    let mut senders = Vec::new();
+                    let mut rest = &self[1..];
+                    while let [name, count, ..] = rest {
+                        let name = name.deserialize()?;
+                        let count: u64 = count.deserialize()?;
+                        let end = 2 + count as usize * RECORD_PACKETS;
+                        let records = rest.get(2..end)?.chunks(RECORD_PACKETS)
+                            .map(|record| record.deserialize())
+                            .collect::<Option<Vec<Record>>>()?;
+                        senders.push((name, records));
+                        rest = &rest[end..];
+                    }
+                    Some(Response::Unread(senders))
+                },
+                10 => Some(Response::Error(self[1..].deserialize()?)),
+                11 => Some(Response::Nick(self.first()?.deserialize()?)),
+                12 => {
+                    let old = self.first()?.deserialize()?;
+                    let new = self.get(1)?.deserialize()?;
+                    Some(Response::Renamed(old, new))
+                },
+                13 => {
+                    let presence = Presence::from_code(*self.first()?.data.first()?)?;
+                    let status = self.get(1)?.deserialize()?;
+                    Some(Response::Status(presence, status))
+                },
+                14 => self[1..].chunks(3)
+                    .map(|contact| contact.deserialize())
+                    .collect::<Option<Vec<(Nickname, Presence, Msg)>>>()
+                    .map(Response::Contacts),
+                15 => {
+                    let (name, presence, status) = self[..].deserialize()?;
+                    Some(Response::PresenceChanged(name, presence, status))
+                },
+                16 => self[1..].iter()
+                    .map(|name| name.deserialize())
+                    .collect::<Option<Vec<Nickname>>>()
+                    .map(Response::Blocked),
+                17 => Some(Response::Ok),
+                18 => self[1..].chunks(BAN_PACKETS)
+                    .map(|ban| ban.deserialize())
+                    .collect::<Option<Vec<Ban>>>()
+                    .map(Response::Bans),
+                19 => Some(Response::Kicked(self.first()?.deserialize()?)),
+                20 => {
+                    let total = self.first()?.deserialize()?;
+                    let addrs = self[1..].chunks(2)
+                        .map(|addr| {
+                            let ip: String = addr.first()?.deserialize()?;
+                            Some((ip.parse().ok()?, addr.get(1)?.deserialize()?))
+                        })
+                        .collect::<Option<Vec<(IpAddr, u64)>>>()?;
+                    Some(Response::Connections(total, addrs))
+                },
+                21 => Some(Response::Broadcast(self.first()?.deserialize()?)),
+                22 => self[1..].chunks(SESSION_PACKETS)
+                    .map(|session| session.deserialize())
+                    .collect::<Option<Vec<SessionInfo>>>()
+                    .map(Response::Sessions),
+                23 => self[1..].chunks(2)
+                    .map(|stat| Some((stat.first()?.deserialize()?, stat.get(1)?.deserialize()?)))
+                    .collect::<Option<Vec<(Msg, u64)>>>()
+                    .map(Response::Stats),
+                24 => {
+                    let next: Option<u64> = packet.deserialize();
+                    let mut matches = Vec::new();
+                    let mut rest = &self[1..];
+                    while !rest.is_empty() {
+                        let (found, after) = Match::take(rest)?;
+                        matches.push(found);
+                        rest = after;
+                    }
+                    Some(Response::Matches(matches, next.map(|next| next as u32)))
+                },
+                25 => {
+                    let id = packet.deserialize()?;
+                    let msg = self.get(1)?.deserialize()?;
+                    Some(Response::MessageEdited(id, msg))
+                },
+                26 => Some(Response::MessageDeleted(packet.deserialize()?)),
+                27 => {
+                    let id = packet.deserialize()?;
+                    let mut reactions = Vec::new();
+                    let mut rest = &self[1..];
+                    while let [emoji, count, ..] = rest {
+                        let emoji = emoji.deserialize()?;
+                        let count: u64 = count.deserialize()?;
+                        let end = 2 + count as usize;
+                        let names = rest.get(2..end)?.iter()
+                            .map(|name| name.deserialize())
+                            .collect::<Option<Vec<Nickname>>>()?;
+                        reactions.push((emoji, names));
+                        rest = &rest[end..];
+                    }
+                    Some(Response::Reactions(id, reactions))
+                },
+                _ => None,
+            }
+        }
+    }
+
+    fn presence_packets(name: &str, presence: Presence, status: &str, num: u8) -> Vec<Packet> {
+        let mut presence_packet = to_packet(1, num);
+        presence_packet.data.push(presence.code());
+        vec![string_packet(name, num), presence_packet, string_packet(status, num)]
+    }
+
+    impl Deserialize<(Nickname, Presence, Msg)> for [Packet] {
+        fn deserialize(&self) -> Option<(Nickname, Presence, Msg)> {
+            let name = self.first()?.deserialize()?;
+            let presence = Presence::from_code(*self.get(1)?.data.first()?)?;
+            let status = self.get(2)?.deserialize()?;
+            Some((name, presence, status))
+        }
+    }
+}
+
+pub mod error {
+    use std::fmt;
+    use crate::{Serialize, Deserialize, Packet, to_packet, u64_packet, Timestamp};
+
+    /// Why the server refused a command. Goes over the wire as a one byte code followed by
+    /// the details, if there are any.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Error {
+        /// Shorter than the minimum length, which is included.
+        NickTooShort(u32),
+        /// Longer than the maximum length, which is included.
+        NickTooLong(u32),
+        /// Contains a character the nickname policy doesn't allow.
+        NickInvalidChar(char),
+        /// Letters from more than one script, which is how look-alike names are made.
+        NickMixedScripts,
+        NickReserved,
+        NickTaken,
+        NotOperator,
+        NoSuchUser,
+        /// Banned until the included time, or for good.
+        Banned(Option<Timestamp>),
+        /// Too many commands, try again in the included number of milliseconds.
+        RateLimited(u64),
+        ServerFull,
+        TooManyConnections,
+        BadToken,
+        /// Something went wrong saving it on the server's end.
+        Storage,
+        NoSuchMessage,
+        /// Reactions are a few characters of emoji or symbols, not text.
+        InvalidReaction,
+        /// The message already has as many different reactions as it can.
+        TooManyReactions,
+        /// The account has a password and it wasn't the one given.
+        WrongPassword,
+        PasswordRequired,
+    }
+
+    impl Error {
+        fn code(&self) -> u8 {
+            match self {
+                Error::NickTooShort(_)    => 0,
+                Error::NickTooLong(_)     => 1,
+                Error::NickInvalidChar(_) => 2,
+                Error::NickReserved       => 3,
+                Error::NickTaken          => 4,
+                Error::NotOperator        => 5,
+                Error::NoSuchUser         => 6,
+                Error::Banned(_)          => 7,
+                Error::RateLimited(_)     => 8,
+                Error::ServerFull         => 9,
+                Error::TooManyConnections => 10,
+                Error::BadToken           => 11,
+                Error::Storage            => 12,
+                Error::NoSuchMessage      => 13,
+                Error::InvalidReaction    => 14,
+                Error::TooManyReactions   => 15,
+                Error::NickMixedScripts   => 16,
+                Error::WrongPassword      => 17,
+                Error::PasswordRequired   => 18,
+            }
+        }
+    }
+
+    impl Serialize for Error {
+        fn serialize(&self) -> Vec<Packet> {
+            let mut code = to_packet(1, 0);
+            code.data.push(self.code());
+            match self {
+                Error::NickTooShort(n) | Error::NickTooLong(n) => vec![code, u64_packet(*n as u64, 0)],
+                Error::NickInvalidChar(c) => vec![code, u64_packet(*c as u64, 0)],
+                Error::Banned(expires) => vec![code, u64_packet(expires.unwrap_or(0), 0)],
+                Error::RateLimited(wait) => vec![code, u64_packet(*wait, 0)],
+                _ => vec![code],
+            }
+        }
+    }
+
+    impl Deserialize<Error> for [Packet] {
+        fn deserialize(&self) -> Option<Error> {
+            let code = *self.first()?.data.first()?;
+            let detail = || -> Option<u64> { self.get(1)?.deserialize() };
+            match code {
+                0 => Some(Error::NickTooShort(detail()? as u32)),
+                1 => Some(Error::NickTooLong(detail()? as u32)),
+                2 => Some(Error::NickInvalidChar(char::from_u32(detail()? as u32)?)),
+                3 => Some(Error::NickReserved),
+                4 => Some(Error::NickTaken),
+                5 => Some(Error::NotOperator),
+                6 => Some(Error::NoSuchUser),
+                7 => Some(Error::Banned(Some(detail()?).filter(|expires| *expires != 0))),
+                8 => Some(Error::RateLimited(detail()?)),
+                9 => Some(Error::ServerFull),
+                10 => Some(Error::TooManyConnections),
+                11 => Some(Error::BadToken),
+                12 => Some(Error::Storage),
+                13 => Some(Error::NoSuchMessage),
+                14 => Some(Error::InvalidReaction),
+                15 => Some(Error::TooManyReactions),
+                16 => Some(Error::NickMixedScripts),
+                17 => Some(Error::WrongPassword),
+                18 => Some(Error::PasswordRequired),
                 _ => None,
             }
         }
     }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Error::NickTooShort(min)  => write!(f, "nickname must be at least {} characters", min),
+                Error::NickTooLong(max)   => write!(f, "nickname can be at most {} characters", max),
+                Error::NickInvalidChar(c) => write!(f, "nickname can't contain {:?}", c),
+                Error::NickReserved       => write!(f, "nickname is reserved"),
+                Error::NickTaken          => write!(f, "nickname is already in use"),
+                Error::NotOperator        => write!(f, "only operators can do that"),
+                Error::NoSuchUser         => write!(f, "no such user is logged in"),
+                Error::Banned(None)       => write!(f, "you are banned"),
+                Error::Banned(Some(t))    => write!(f, "you are banned until {}", t),
+                Error::RateLimited(wait)  => write!(f, "too many commands, try again in {}ms", wait),
+                Error::ServerFull         => write!(f, "the server is full"),
+                Error::TooManyConnections => write!(f, "too many connections from your address"),
+                Error::BadToken           => write!(f, "wrong admin token"),
+                Error::Storage            => write!(f, "the server could not save it"),
+                Error::NoSuchMessage      => write!(f, "no such message"),
+                Error::InvalidReaction    => write!(f, "not a reaction"),
+                Error::TooManyReactions   => write!(f, "the message has too many reactions"),
+                Error::NickMixedScripts   => write!(f, "nickname can't mix letters from different scripts"),
+                Error::WrongPassword      => write!(f, "wrong password"),
+                Error::PasswordRequired   => write!(f, "logging in needs a password"),
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -215,6 +1005,26 @@ impl Packet {
             })
             .collect()
     }
+
+    /// Reads one frame, the packets up to the zero length terminator, from a blocking reader.
+    /// Returns `None` once the reader is exhausted.
+    pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<Packet>>> {
+        let mut packets = Vec::new();
+        loop {
+            let mut amount = [0; 4];
+            match reader.read_exact(&mut amount) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && packets.is_empty() => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let amount = u32::from_be_bytes(amount);
+            if amount == 0 { return Ok(Some(packets)); }
+            let mut buffer = vec![0; amount as usize];
+            reader.read_exact(&mut buffer)?;
+            let data_type = buffer.remove(0);
+            packets.push(Packet::new(amount, data_type, buffer));
+        }
+    }
 }
 
 // This function is really bad
@@ -297,11 +1107,42 @@ impl Serialize for SocketAddr {
     }
 }
 
+impl Deserialize<String> for Packet {
+    fn deserialize(&self) -> Option<String> {
+        String::from_utf8(self.data.to_vec()).ok()
+    }
+}
+
+impl Deserialize<u64> for Packet {
+    fn deserialize(&self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.data.get(..8)?);
+        Some(u64::from_be_bytes(bytes))
+    }
+}
+
 fn to_packet(size: usize, num: u8) -> Packet {
     let size = size + 1;
     Packet { amount: size as u32, data: Vec::new(), data_type: num }
 }
 
+fn string_packet(string: &str, num: u8) -> Packet {
+    let mut packet = to_packet(string.len(), num);
+    packet.data.extend(string.bytes());
+    packet
+}
+
+fn u64_packet(n: u64, num: u8) -> Packet {
+    let mut packet = to_packet(8, num);
+    packet.data.extend_from_slice(&n.to_be_bytes());
+    packet
+}
+
+/// Milliseconds since the unix epoch, used to timestamp messages.
+pub fn now() -> Timestamp {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
 pub trait Serialize {
     fn serialize(&self) -> Vec<Packet>;
 }
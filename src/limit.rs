@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::str::FromStr;
+
+use crate::request::Command;
+use crate::Timestamp;
+
+// Past this many buckets the ones that have refilled completely are dropped, they are the same
+// as a fresh one anyway
+const PRUNE_AT: usize = 4096;
+
+/// `count` commands every `secs` seconds, written as `count/secs`. Unused commands add up to a
+/// burst of at most `count`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub count: u32,
+    pub secs: u32,
+}
+
+impl Rate {
+    fn per_milli(self) -> f64 {
+        self.count as f64 / (self.secs as f64 * 1000.0)
+    }
+}
+
+impl FromStr for Rate {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Rate, ()> {
+        let (count, secs) = s.split_once('/').ok_or(())?;
+        let count = count.trim().parse().map_err(|_| ())?;
+        let secs = secs.trim().parse().map_err(|_| ())?;
+        if count == 0 || secs == 0 { return Err(()); }
+        Ok(Rate { count, secs })
+    }
+}
+
+/// The groups of commands that are limited separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Login,
+    Message,
+    Search,
+    /// Batches of messages from an archive, `Command::Import`.
+    Import,
+    Other,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 5] = [Kind::Login, Kind::Message, Kind::Search, Kind::Import, Kind::Other];
+
+    /// `None` for commands that are never limited.
+    pub fn of(command: &Command) -> Option<Kind> {
+        match command {
+            Command::Exit => None,
+            Command::Login(..) => Some(Kind::Login),
+            Command::Message(..) | Command::Edit(..) => Some(Kind::Message),
+            Command::Search(..) => Some(Kind::Search),
+            Command::SearchMessages { .. } => Some(Kind::Search),
+            Command::Import(..) => Some(Kind::Import),
+            _ => Some(Kind::Other),
+        }
+    }
+}
+
+/// The rate for every kind of command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rates {
+    pub login: Rate,
+    pub message: Rate,
+    pub search: Rate,
+    pub import: Rate,
+    pub other: Rate,
+}
+
+impl Rates {
+    pub fn get(&self, kind: Kind) -> Rate {
+        match kind {
+            Kind::Login   => self.login,
+            Kind::Message => self.message,
+            Kind::Search  => self.search,
+            Kind::Import  => self.import,
+            Kind::Other   => self.other,
+        }
+    }
+
+    /// Every rate multiplied by `n`, for limits shared by several connections.
+    pub fn times(&self, n: u32) -> Rates {
+        let times = |rate: Rate| Rate { count: rate.count.saturating_mul(n), secs: rate.secs };
+        Rates {
+            login: times(self.login),
+            message: times(self.message),
+            search: times(self.search),
+            import: times(self.import),
+            other: times(self.other),
+        }
+    }
+}
+
+impl Default for Rates {
+    fn default() -> Rates {
+        Rates {
+            login: Rate { count: 5, secs: 60 },
+            message: Rate { count: 20, secs: 10 },
+            search: Rate { count: 10, secs: 10 },
+            import: Rate { count: 10, secs: 10 },
+            other: Rate { count: 30, secs: 10 },
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Timestamp,
+}
+
+/// Token buckets for every key and kind of command.
+pub struct Limiter<K> {
+    rates: Rates,
+    buckets: HashMap<(K, Kind), Bucket>,
+}
+
+impl<K: Hash + Eq + Clone> Limiter<K> {
+    pub fn new(rates: Rates) -> Limiter<K> {
+        Limiter { rates, buckets: HashMap::new() }
+    }
+
+    /// Milliseconds until `key` may run another command of the kind, 0 if it may right away.
+    pub fn wait(&mut self, key: &K, kind: Kind, now: Timestamp) -> u64 {
+        if self.buckets.len() > PRUNE_AT {
+            let rates = self.rates;
+            self.buckets.retain(|(_, kind), bucket| refill(bucket, rates.get(*kind), now) < rates.get(*kind).count as f64);
+        }
+        let rate = self.rates.get(kind);
+        let bucket = self.buckets.entry((key.clone(), kind))
+            .or_insert(Bucket { tokens: rate.count as f64, updated: now });
+        let tokens = refill(bucket, rate, now);
+        if tokens >= 1.0 { 0 } else { ((1.0 - tokens) / rate.per_milli()).ceil() as u64 }
+    }
+
+    /// Uses up a token, call `wait` first to see if there is one.
+    pub fn take(&mut self, key: &K, kind: Kind) {
+        if let Some(bucket) = self.buckets.get_mut(&(key.clone(), kind)) {
+            bucket.tokens -= 1.0;
+        }
+    }
+
+    /// Hands `old`'s buckets to `new`, so a key that changes doesn't start over with full
+    /// ones. Where `new` has a bucket already the emptier of the two is kept.
+    pub fn rename(&mut self, old: &K, new: K) {
+        for kind in Kind::ALL {
+            let bucket = match self.buckets.remove(&(old.clone(), kind)) {
+                Some(bucket) => bucket,
+                None => continue,
+            };
+            let fuller = self.buckets.get(&(new.clone(), kind)).is_none_or(|other| other.tokens > bucket.tokens);
+            if fuller { self.buckets.insert((new.clone(), kind), bucket); }
+        }
+    }
+}
+
+fn refill(bucket: &mut Bucket, rate: Rate, now: Timestamp) -> f64 {
+    let elapsed = now.saturating_sub(bucket.updated) as f64;
+    bucket.tokens = (bucket.tokens + elapsed * rate.per_milli()).min(rate.count as f64);
+    bucket.updated = now.max(bucket.updated);
+    bucket.tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> Limiter<&'static str> {
+        Limiter::new(Rates { message: Rate { count: 2, secs: 10 }, ..Rates::default() })
+    }
+
+    #[test]
+    fn rates_parse_as_count_per_seconds() {
+        assert_eq!("5/60".parse(), Ok(Rate { count: 5, secs: 60 }));
+        assert!("0/60".parse::<Rate>().is_err());
+        assert!("5".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn bursts_wait_for_the_bucket_to_refill() {
+        let mut limits = limiter();
+        for _ in 0..2 {
+            assert_eq!(limits.wait(&"alice", Kind::Message, 0), 0);
+            limits.take(&"alice", Kind::Message);
+        }
+        assert_eq!(limits.wait(&"alice", Kind::Message, 0), 5000);
+        assert_eq!(limits.wait(&"alice", Kind::Message, 4000), 1000);
+        assert_eq!(limits.wait(&"alice", Kind::Message, 5000), 0);
+    }
+
+    #[test]
+    fn keys_and_kinds_are_limited_apart() {
+        let mut limits = limiter();
+        for _ in 0..2 {
+            limits.wait(&"alice", Kind::Message, 0);
+            limits.take(&"alice", Kind::Message);
+        }
+        assert_eq!(limits.wait(&"bob", Kind::Message, 0), 0);
+        assert_eq!(limits.wait(&"alice", Kind::Search, 0), 0);
+    }
+
+    #[test]
+    fn imports_have_a_limit_of_their_own() {
+        assert_eq!(Kind::of(&Command::Import(Vec::new())), Some(Kind::Import));
+        let rates: Rates = Rates { import: Rate { count: 1, secs: 1 }, ..Rates::default() };
+        assert_eq!(rates.times(4).import, Rate { count: 4, secs: 1 });
+    }
+
+    #[test]
+    fn renamed_keys_keep_their_buckets() {
+        let mut limits = limiter();
+        for _ in 0..2 {
+            limits.wait(&"alice", Kind::Message, 0);
+            limits.take(&"alice", Kind::Message);
+        }
+        limits.wait(&"carol", Kind::Message, 0);
+        limits.rename(&"alice", "alicia");
+        assert_eq!(limits.wait(&"alicia", Kind::Message, 0), 5000);
+        assert_eq!(limits.wait(&"alice", Kind::Message, 0), 0);
+        // Taking a name with fuller buckets doesn't refill them either
+        limits.rename(&"alicia", "carol");
+        assert_eq!(limits.wait(&"carol", Kind::Message, 0), 5000);
+    }
+}
@@ -1,106 +1,1280 @@
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpStream, TcpListener};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, Notify};
 
 use chat_server::{Packet, Deserialize, Serialize};
 use chat_server::request::Command;
-use chat_server::respond::Response;
+use chat_server::respond::{Match, Response, SessionInfo, UserInfo};
+use chat_server::presence::Presence;
+use chat_server::history::Record;
+use chat_server::config::{Config, LogFormat};
+use chat_server::error::Error as ChatError;
+use chat_server::nick;
+use chat_server::password;
+use chat_server::search::{self, SearchMode};
+use chat_server::ban::{Ban, BanTarget};
+use chat_server::limit::{Kind, Limiter};
+use chat_server::metrics::Metrics;
+use chat_server::http::{self, Reply, Request};
+use chat_server::json::Value;
+use chat_server::audit::Audit;
+use chat_server::storage::{self, Account, List, Storage};
 // use echo_server::{Packet, Deserialize, Command};
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::error::Error;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::cell::Cell;
+use std::time::{Duration, Instant};
 
-type Users = Arc<Mutex<HashMap<String, SocketAddr>>>;
+use tracing::{debug, error, info, warn, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+
+// Both are keyed by `nick::key`
+type Users = HashMap<String, User>;
+// When each muted user may talk again, `None` for once they are unmuted
+type Mutes = HashMap<String, Option<u64>>;
+// Everything sent here is written to the session's socket, in order
+type Outbox = mpsc::UnboundedSender<Option<Response>>;
+
+const CONFIG_FILE: &str = "chat_server.conf";
+const MAX_HISTORY_PAGE: u32 = 100;
+const MAX_SEARCH_PAGE: u32 = 50;
+// Messages shown on either side of a message search result
+const SEARCH_CONTEXT: usize = 2;
+// Characters in a reaction, enough for emoji joined into one
+const MAX_REACTION_LEN: usize = 10;
+// Different reactions a message can have
+const MAX_REACTIONS: usize = 20;
+const MAX_STATUS_LEN: usize = 100;
+
+struct User {
+    name: String, // As the user wrote it
+    addr: SocketAddr,
+    peer: SocketAddr, // Where the connection comes from, `addr` is whatever the client told us
+    outbox: Outbox,
+    kick: Arc<Notify>,
+    presence: Presence,
+    status: String,
+    operator: bool,
+    // Stats for the admin API
+    logged_in: u64,
+    commands: u64,
+    messages_sent: u64,
+}
+
+impl User {
+    // Tells the user why, then has their connection closed
+    fn kick(&self, reason: String) {
+        let _ = self.outbox.send(Some(Response::Kicked(reason)));
+        self.kick.notify_one();
+    }
+}
+
+impl User {
+    fn info(&self) -> UserInfo {
+        UserInfo { name: self.name.clone(), addr: self.addr, presence: self.presence, status: self.status.clone() }
+    }
+
+    fn session(&self) -> SessionInfo {
+        SessionInfo {
+            name: self.name.clone(),
+            peer: self.peer,
+            addr: self.addr,
+            presence: self.presence,
+            operator: self.operator,
+            logged_in: self.logged_in,
+            commands: self.commands,
+            messages_sent: self.messages_sent,
+        }
+    }
+}
+
+struct State {
+    config: Config,
+    users: Mutex<Users>,
+    // Accounts, contact lists, bans, the message history and messages waiting for users that
+    // are offline. Only names with an account get messages queued, so messages to names that
+    // were never used still fail. Taken after `users` when both are needed.
+    storage: Mutex<Box<dyn Storage>>,
+    mutes: Mutex<Mutes>,
+    // Rate limits that outlast a single connection
+    account_limits: Mutex<Limiter<String>>,
+    ip_limits: Mutex<Limiter<IpAddr>>,
+    connections: Mutex<Connections>,
+    metrics: Metrics,
+    audit: Mutex<Audit>,
+}
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// A connection's place in `State::connections`, given back when it is dropped
+struct Slot {
+    state: Arc<State>,
+    ip: IpAddr,
+}
+
+impl Slot {
+    fn take(state: &Arc<State>, ip: IpAddr) -> Result<Slot, ChatError> {
+        let mut connections = state.connections.lock().unwrap();
+        if connections.total >= state.config.max_connections { return Err(ChatError::ServerFull); }
+        let from_ip = connections.per_ip.entry(ip).or_default();
+        if *from_ip >= state.config.max_connections_per_ip { return Err(ChatError::TooManyConnections); }
+        *from_ip += 1;
+        connections.total += 1;
+        Ok(Slot { state: state.clone(), ip })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut connections = self.state.connections.lock().unwrap();
+        connections.total -= 1;
+        if let Some(from_ip) = connections.per_ip.get_mut(&self.ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 { connections.per_ip.remove(&self.ip); }
+        }
+    }
+}
+
+// What a connection hands to the user it logs in
+struct Session {
+    peer: SocketAddr,
+    // Authenticated with the admin token
+    admin: AtomicBool,
+    outbox: Outbox,
+    kick: Arc<Notify>,
+}
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let users: Users = Arc::new(Mutex::new(HashMap::new()));
-    let listener = TcpListener::bind("127.0.0.1:6142").await.unwrap();
+    let config = Config::load(std::env::args().nth(1).unwrap_or_else(|| CONFIG_FILE.to_string()))?;
+    init_logging(&config);
+    let listener = TcpListener::bind(&config.listen).await?;
+    let state = Arc::new(State {
+        users: Mutex::new(HashMap::new()),
+        storage: Mutex::new(storage::open(&config)?),
+        mutes: Mutex::new(HashMap::new()),
+        account_limits: Mutex::new(Limiter::new(config.rate_limit)),
+        ip_limits: Mutex::new(Limiter::new(config.ip_rate_limit)),
+        connections: Mutex::new(Connections::default()),
+        metrics: Metrics::default(),
+        audit: Mutex::new(Audit::open(&config.audit_file)?),
+        config,
+    });
+
+    if !state.config.metrics_listen.is_empty() {
+        let listener = TcpListener::bind(&state.config.metrics_listen).await?;
+        info!(listen = %state.config.metrics_listen, "serving metrics");
+        tokio::spawn(serve_metrics(listener, state.clone()));
+    }
+    if !state.config.admin_listen.is_empty() {
+        let listener = TcpListener::bind(&state.config.admin_listen).await?;
+        info!(listen = %state.config.admin_listen, "serving admin api");
+        tokio::spawn(serve_admin(listener, state.clone()));
+    }
+    tokio::spawn(sweep(state.clone()));
 
     loop {
         let (socket, addr) = listener.accept().await?;
-        println!("Client connected from {}", addr);
-        let users = users.clone();
+        let slot = match Slot::take(&state, addr.ip()) {
+            Ok(slot) => slot,
+            Err(e) => {
+                Metrics::inc(&state.metrics.rejected_connections);
+                warn!(peer = %addr, error = %e, "turned away");
+                audit(&state, "turned_away", Some(addr), vec![("reason", e.to_string().into())]);
+                tokio::spawn(async move {
+                    let (_, mut writer) = socket.into_split();
+                    let _ = response(&mut writer, Some(Response::Error(e))).await;
+                });
+                continue;
+            },
+        };
+        Metrics::inc(&state.metrics.connections);
+        let state = state.clone();
 
+        // Everything logged for the connection carries its address, and the nickname once known
+        let span = tracing::info_span!("connection", peer = %addr, nick = tracing::field::Empty);
         tokio::spawn(async move {
-            match process_socket(socket, users).await {
-                Err(e) => println!("{:?}", e),
-                Ok(()) => return (),
+            info!("connected");
+            match process_socket(socket, addr, state).await {
+                Ok(()) => info!("disconnected"),
+                Err(e) => info!(error = %e, "disconnected"),
             }
-        });
+            drop(slot);
+        }.instrument(span));
     }
 }
 
-async fn process_socket(mut socket: TcpStream, users: Users) -> Result<(), Box<dyn Error>> {
+async fn process_socket(socket: TcpStream, peer: SocketAddr, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    let (mut reader, mut writer) = socket.into_split();
+    let banned = stored(state.storage.lock().unwrap().bans()).ip(peer.ip(), chat_server::now()).map(|ban| ban.expires);
+    if let Some(expires) = banned {
+        info!("turned away, address is banned");
+        audit(&state, "turned_away", Some(peer), vec![("reason", "address is banned".into())]);
+        return response(&mut writer, Some(Response::Error(ChatError::Banned(expires)))).await;
+    }
+
+    let (outbox, mut responses) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(res) = responses.recv().await {
+            if let Err(e) = response(&mut writer, res).await {
+                warn!(error = %e, "could not write response");
+                break;
+            }
+        }
+    }.instrument(Span::current()));
+
+    let session = Session { peer, admin: AtomicBool::new(false), outbox, kick: Arc::new(Notify::new()) };
     let mut curr_user = Cell::new(None);
+    let res = serve(&mut reader, &state, &session, &mut curr_user).await;
+    // However the connection ended, the user shouldn't stay behind
+    let user = curr_user.take().map(|(name, _)| name);
+    if let Some(name) = &user {
+        leave(&state, name);
+    }
+    let reason = match &res {
+        Ok(reason) => reason.to_string(),
+        Err(e) => match e.downcast_ref::<io::Error>() {
+            Some(e) if e.kind() == io::ErrorKind::UnexpectedEof => "closed".to_string(),
+            _ => e.to_string(),
+        },
+    };
+    audit(&state, "disconnect", Some(peer), vec![("user", user.into()), ("reason", reason.into())]);
+    res.map(|_| ())
+}
+
+async fn serve(reader: &mut OwnedReadHalf,
+               state: &State,
+               session: &Session,
+               curr_user: &mut Cell<Option<(String, SocketAddr)>>) -> Result<&'static str, Box<dyn Error>> {
+    let mut limits = Limiter::new(state.config.rate_limit);
+    let connection = Span::current();
+    let mut logged_nick = None;
     loop {
-        let bytes = request(&mut socket).await?;
-        let command = bytes.deserialize().unwrap(); // NOTE: Handle this
-        let res = handle_command(command, &users, &mut curr_user);
-        println!("{:?}", res);
-        response(&mut socket, res).await?;
+        let bytes = tokio::select! {
+            bytes = request(reader) => bytes?,
+            _ = session.kick.notified() => return Ok("kicked"),
+        };
+        let command: Command = match bytes.deserialize() {
+            Some(command) => command,
+            None => {
+                // Answered with an empty frame so the client isn't left waiting
+                Metrics::inc(&state.metrics.decode_errors);
+                warn!("could not decode request");
+                if session.outbox.send(None).is_err() { return Ok("closed"); }
+                continue;
+            },
+        };
+        let exit = matches!(command, Command::Exit);
+        let name = command.name();
+        let span = tracing::info_span!("command", command = name);
+        let audited = audit_fields(&command);
+        let user = curr_user.get_mut().as_ref().map(|(name, _)| name.clone());
+        let started = Instant::now();
+        let res = span.in_scope(|| match rate_limit(state, session, &mut limits, curr_user.get_mut(), &command) {
+            0 => handle_command(command, state, session, curr_user),
+            wait => {
+                debug!(wait, "rate limited");
+                Some(Response::Error(ChatError::RateLimited(wait)))
+            },
+        });
+        state.metrics.observe(name, started.elapsed());
+        if let Some(mut fields) = audited {
+            fields.push(("user", user.into()));
+            fields.push(("ok", (!matches!(res, None | Some(Response::Error(_)))).into()));
+            if let Some(Response::Error(e)) = &res {
+                fields.push(("error", e.to_string().into()));
+            }
+            audit(state, name, Some(session.peer), fields);
+        }
+        let routed = matches!(res, Some(Response::Message(..)) | Some(Response::Queued(_)));
+        match (name, &res) {
+            ("login", Some(Response::Login(..))) => Metrics::inc(&state.metrics.logins),
+            ("login", _) => Metrics::inc(&state.metrics.failed_logins),
+            ("message", _) if routed => Metrics::inc(&state.metrics.messages_routed),
+            _ => (),
+        }
+        if let Some((name, _)) = curr_user.get_mut() {
+            if let Some(user) = state.users.lock().unwrap().get_mut(&nick::key(name)) {
+                user.commands += 1;
+                user.messages_sent += routed as u64;
+            }
+        }
+        // Recorded again only on a change, text output repeats the field for every record
+        let nick = curr_user.get_mut().as_ref().map(|(name, _)| name.clone());
+        if nick.is_some() && nick != logged_nick {
+            connection.record("nick", nick.as_deref().unwrap_or_default());
+            logged_nick = nick;
+        }
+        span.in_scope(|| debug!(response = ?res));
+        if exit { return Ok("exit"); }
+        if session.outbox.send(res).is_err() { return Ok("closed"); }
+    }
+}
+
+// The details worth keeping in the audit log for commands that belong there. Secrets are left
+// out, and so are message contents apart from broadcasts, which everyone online sees anyway.
+fn audit_fields(command: &Command) -> Option<Vec<(&'static str, Value)>> {
+    let target = |target: &BanTarget| match target {
+        BanTarget::Nick(name) => Value::object([("nick", name.as_str().into())]),
+        BanTarget::Ip(range) => Value::object([("ip", range.to_string().into())]),
+    };
+    match command {
+        Command::Login(name, _, _) => Some(vec![("nick", name.as_str().into())]),
+        Command::Logout | Command::Oper(_) | Command::Admin(_) => Some(vec![]),
+        Command::Nick(new) => Some(vec![("new", new.as_str().into())]),
+        Command::Grant(name) | Command::Kick(name) | Command::Unmute(name) => Some(vec![("target", name.as_str().into())]),
+        Command::Mute(name, secs) => Some(vec![("target", name.as_str().into()), ("seconds", (*secs).into())]),
+        Command::Ban(ban, secs) => Some(vec![("target", target(ban)), ("seconds", (*secs).into())]),
+        Command::Unban(ban) => Some(vec![("target", target(ban))]),
+        Command::Broadcast(msg) => Some(vec![("message", msg.as_str().into())]),
+        Command::Export(name) => Some(vec![("target", name.as_str().into())]),
+        Command::Import(records) => Some(vec![("messages", (records.len() as u64).into())]),
+        _ => None,
+    }
+}
+
+fn audit(state: &State, event: &str, peer: Option<SocketAddr>, fields: Vec<(&str, Value)>) {
+    if let Err(e) = state.audit.lock().unwrap().record(event, peer, fields) {
+        error!(error = %e, event, "could not write to the audit log");
+    }
+}
+
+// Commands count against the connection, the account and the address they come from, and only go
+// through if none of them is over its limit. Returns how long to wait if one is.
+fn rate_limit(state: &State,
+              session: &Session,
+              limits: &mut Limiter<()>,
+              curr_user: &Option<(String, SocketAddr)>,
+              command: &Command) -> u64 {
+    let kind = match Kind::of(command) {
+        Some(kind) => kind,
+        None => return 0,
+    };
+    let now = chat_server::now();
+    let account = curr_user.as_ref().map(|(name, _)| nick::key(name));
+    let mut accounts = state.account_limits.lock().unwrap();
+    let mut ips = state.ip_limits.lock().unwrap();
+    let ip = session.peer.ip();
+
+    let wait = limits.wait(&(), kind, now)
+        .max(ips.wait(&ip, kind, now))
+        .max(account.as_ref().map_or(0, |account| accounts.wait(account, kind, now)));
+    if wait == 0 {
+        limits.take(&(), kind);
+        ips.take(&ip, kind);
+        if let Some(account) = &account { accounts.take(account, kind); }
     }
+    wait
 }
 
 fn handle_command(command: Command, 
-                  users: &Users, 
+                  state: &State, 
+                  session: &Session,
                   curr_user: &mut Cell<Option<(String, SocketAddr)>>) -> Option<Response> {
     match command {
-        Command::Login(name, addr) => {
-            if name == "" { return None; }
-            let mut users = users.lock().unwrap();
-            if users.contains_key(&name) { None }
+        Command::Login(name, addr, password) => {
+            let name = match state.config.nick.validate(&name) {
+                Ok(name) => name,
+                Err(e) => return Some(Response::Error(e)),
+            };
+            let Some(password) = password.filter(|password| !password.is_empty()) else {
+                return Some(Response::Error(ChatError::PasswordRequired));
+            };
+            if let Some(ban) = stored(state.storage.lock().unwrap().bans()).nick(&name, chat_server::now()) {
+                return Some(Response::Error(ChatError::Banned(ban.expires)));
+            }
+            let key = nick::key(&name);
+            // Hashing is slow on purpose, so it is done before taking the locks everyone else
+            // waits on, and off the threads other connections run on. An account without a
+            // password yet is claimed with this one.
+            let secret = stored(state.storage.lock().unwrap().account(&key)).and_then(|account| account.secret);
+            let (verified, new_secret) = tokio::task::block_in_place(|| match &secret {
+                Some(secret) => (password::verify(&password, secret), None),
+                None => (true, Some(password::hash(&password))),
+            });
+            if !verified { return Some(Response::Error(ChatError::WrongPassword)); }
+            let mut users = state.users.lock().unwrap();
+            let storage = state.storage.lock().unwrap();
+            let account = stored(storage.account(&key));
+            // Whoever got in between checking the password and now may have claimed the account
+            if account.as_ref().and_then(|account| account.secret.as_ref()) != secret.as_ref() {
+                return Some(Response::Error(ChatError::WrongPassword));
+            }
+            drop(storage);
+            if users.contains_key(&key) { Some(Response::Error(ChatError::NickTaken)) }
             else {
+                info!(nick = %name, "logged in");
                 curr_user.set(Some((name.clone(), addr)));
-                users.insert(name.clone(), addr);
+                users.insert(key.clone(), User {
+                    name: name.clone(),
+                    addr,
+                    peer: session.peer,
+                    outbox: session.outbox.clone(),
+                    kick: session.kick.clone(),
+                    presence: Presence::Online,
+                    status: String::new(),
+                    operator: false,
+                    logged_in: chat_server::now(),
+                    commands: 0,
+                    messages_sent: 0,
+                });
+                notify_contacts(state, &users, &name, Presence::Online, "");
+                let mut storage = state.storage.lock().unwrap();
+                let now = chat_server::now();
+                let created = account.map_or(now, |account| account.created);
+                let secret = new_secret.or(secret);
+                if let Err(e) = storage.put_account(&Account { name: name.clone(), created, last_seen: now, secret }) {
+                    error!(error = %e, "could not save account");
+                }
                 Some(Response::Login(name, addr))
             }
         },
-        Command::Search(name) => {
-            let users = users.lock().unwrap();
-            if name == "" { None }
-            else if name == "all" {
-                let users: Vec<(String, SocketAddr)> = users.keys()
-                        .cloned().zip(users.values().cloned()).collect();
-                if users.len() == 0 { None }
-                else { Some(Response::Search(users)) }
+        Command::Search(mode, pattern, page) => {
+            // Only for those logged in, there would be no telling who blocked anyone else
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            if pattern.is_empty() { return None; }
+            let me = nick::key(name);
+            let users = state.users.lock().unwrap();
+            let pattern = nick::key(&pattern);
+            let blocked_by = stored(state.storage.lock().unwrap().listed_by(List::Blocks, &me));
+            // Invisible users only find themselves, and nobody finds those who blocked them
+            let visible = users.iter()
+                .filter(|(key, user)| user.presence != Presence::Invisible || **key == me)
+                .filter(|(key, _)| !blocked_by.contains(key));
+            let mut found: Vec<(u32, &String, &User)> = if mode == SearchMode::Exact && pattern == nick::ALL {
+                visible.map(|(key, user)| (0, key, user)).collect()
             } else {
-                match users.get(&name) {
-                    Some(addr) => Some(Response::Search(Vec::from([(name, *addr)]))),
-                    None       => None
-                }
-            }
+                visible
+                    .filter_map(|(key, user)| search::rank(mode, &pattern, key).map(|rank| (rank, key, user)))
+                    .collect()
+            };
+            // Ties are broken by name so pages don't shift around between requests
+            found.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+            let limit = page.limit.clamp(1, MAX_SEARCH_PAGE) as usize;
+            let offset = page.offset as usize;
+            let next = if found.len() > offset + limit { Some((offset + limit) as u32) } else { None };
+            let users: Vec<UserInfo> = found.into_iter()
+                .skip(offset)
+                .take(limit)
+                .map(|(_, _, user)| user.info())
+                .collect();
+            Some(Response::Search(users, next))
         },
-        Command::Logout => if let Some((name, _)) = curr_user.get_mut() {
-            let mut users = users.lock().unwrap();
-            users.remove(name);
+        Command::Logout => if let Some((name, _)) = curr_user.take() {
+            leave(state, &name);
             Some(Response::Logout)
         } else {
             None
         },
-        Command::Exit   => {
-            if let Some((name, _)) = curr_user.get_mut() {
-                let mut users = users.lock().unwrap();
-                users.remove(name);
+        // The connection closes right after, which logs the user out
+        Command::Exit   => Some(Response::Exit),
+        Command::Message(name, id, msg, lifetime) => {
+            let from = match curr_user.get_mut() {
+                Some((from, _)) => from.clone(),
+                None => return Some(Response::Failed(id, "you are not logged in".to_string())),
+            };
+            if is_muted(&state.mutes.lock().unwrap(), &nick::key(&from)) {
+                return Some(Response::Failed(id, "you are muted".to_string()));
+            }
+            let key = nick::key(&name);
+            // Being blocked looks the same as the user not being there
+            if is_blocked(&**state.storage.lock().unwrap(), &key, &nick::key(&from)) {
+                return Some(Response::Failed(id, format!("{} is not logged in", name)));
+            }
+            // Invisible users get their messages the way offline ones do, so sending one doesn't
+            // give away that they are around
+            let (name, addr) = match state.users.lock().unwrap().get(&key) {
+                Some(user) if user.presence != Presence::Invisible => (user.name.clone(), Some(user.addr)),
+                Some(user) => (user.name.clone(), None),
+                None => (name, None),
+            };
+            let mut storage = state.storage.lock().unwrap();
+            if addr.is_none() && stored(storage.account(&key)).is_none() {
+                return Some(Response::Failed(id, format!("{} is not logged in", name)));
+            }
+            // A retry gets the first attempt back as it was stored, whatever text it came with.
+            // If that attempt is still waiting in the queue it is left there, so it isn't
+            // delivered now and again once the recipient asks for their messages.
+            let (record, queued) = match stored(storage.message(id)) {
+                Some(record) if record.from == from && nick::key(&record.to) == key => {
+                    let queued = stored(storage.is_queued(&key, id));
+                    (record, queued)
+                },
+                // Ids are unique across the server. Someone else's id fails like any other
+                // message that can't be stored, so nobody learns which ids are taken.
+                Some(_) => return Some(Response::Failed(id, "could not store message".to_string())),
+                None => {
+                    let timestamp = chat_server::now();
+                    let expires = lifetime.map(|secs| timestamp.saturating_add(secs.saturating_mul(1000)));
+                    let record = Record { id, from, to: name.clone(), timestamp, msg, expires };
+                    if let Err(e) = storage.add_message(&record) {
+                        error!(error = %e, "could not store message");
+                        return Some(Response::Failed(id, "could not store message".to_string()));
+                    }
+                    (record, false)
+                },
+            };
+            match addr {
+                _ if queued => Some(Response::Queued(id)),
+                Some(addr) => Some(Response::Message(name, id, record.timestamp, record.msg, addr, record.expires)),
+                None => {
+                    if let Err(e) = storage.queue(&key, &record) {
+                        error!(error = %e, "could not store message");
+                        return Some(Response::Failed(id, "could not store message".to_string()));
+                    }
+                    Some(Response::Queued(id))
+                },
             }
-            Some(Response::Exit)
         },
-        Command::Message(name, msg) => {
-            let users = users.lock().unwrap();
-            match users.get(&name) {
-                Some(addr) => Some(Response::Message(name, msg, *addr)),
-                None => None,
+        Command::Show => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            let queued = stored(state.storage.lock().unwrap().take_queued(&nick::key(name)));
+            let now = chat_server::now();
+            let mut senders: Vec<(String, Vec<Record>)> = Vec::new();
+            // Messages that expired since the last sweep are as good as gone
+            for record in queued.into_iter().filter(|record| !record.expired(now)) {
+                match senders.iter_mut().find(|(sender, _)| *sender == record.from) {
+                    Some((_, records)) => records.push(record),
+                    None => senders.push((record.from.clone(), vec![record])),
+                }
             }
+            senders.sort_by(|a, b| a.0.cmp(&b.0));
+            Some(Response::Unread(senders))
+        },
+        Command::History { peer, before, limit } => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            let limit = limit.min(MAX_HISTORY_PAGE) as usize;
+            let mut records = stored(state.storage.lock().unwrap().conversation(name, &peer, before, limit));
+            let now = chat_server::now();
+            records.retain(|record| !record.expired(now));
+            Some(Response::History(records))
+        },
+        Command::SearchMessages { query, peer, since, page } => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            let key = nick::key(name);
+            let storage = state.storage.lock().unwrap();
+            let now = chat_server::now();
+            let mut found = stored(storage.search_messages(&key, &query, peer.as_deref(), since));
+            found.retain(|record| !record.expired(now));
+            let limit = page.limit.clamp(1, MAX_SEARCH_PAGE) as usize;
+            let offset = page.offset as usize;
+            let next = if found.len() > offset + limit { Some((offset + limit) as u32) } else { None };
+            let matches = found.into_iter()
+                .skip(offset)
+                .take(limit)
+                .map(|record| {
+                    let mut before = stored(storage.conversation(&record.from, &record.to, Some(record.position()), SEARCH_CONTEXT));
+                    let mut after = stored(storage.following(&record.from, &record.to, record.position(), SEARCH_CONTEXT));
+                    before.retain(|record| !record.expired(now));
+                    after.retain(|record| !record.expired(now));
+                    Match { record, before, after }
+                })
+                .collect();
+            Some(Response::Matches(matches, next))
         },
-        Command::Show => None,
+        Command::Edit(id, msg) => {
+            let record = match changeable(state, session, curr_user.get_mut(), id) {
+                Ok(record) => record,
+                Err(e) => return Some(Response::Error(e)),
+            };
+            let me = curr_user.get_mut().as_ref().map(|(name, _)| nick::key(name));
+            if me.as_ref().is_some_and(|me| is_muted(&state.mutes.lock().unwrap(), me)) {
+                return Some(Response::Failed(id, "you are muted".to_string()));
+            }
+            if let Err(e) = state.storage.lock().unwrap().edit_message(id, &msg) {
+                error!(error = %e, "could not edit message");
+                return Some(Response::Error(ChatError::Storage));
+            }
+            notify_conversation(state, &record, me.as_deref(), || Response::MessageEdited(id, msg.clone()));
+            Some(Response::Ok)
+        },
+        Command::Delete(id) => {
+            let record = match changeable(state, session, curr_user.get_mut(), id) {
+                Ok(record) => record,
+                Err(e) => return Some(Response::Error(e)),
+            };
+            if let Err(e) = state.storage.lock().unwrap().delete_message(id) {
+                error!(error = %e, "could not delete message");
+                return Some(Response::Error(ChatError::Storage));
+            }
+            let me = curr_user.get_mut().as_ref().map(|(name, _)| nick::key(name));
+            notify_conversation(state, &record, me.as_deref(), || Response::MessageDeleted(id));
+            Some(Response::Ok)
+        },
+        Command::React { message_id, emoji } => react(state, curr_user.get_mut(), message_id, &emoji, true),
+        Command::Unreact { message_id, emoji } => react(state, curr_user.get_mut(), message_id, &emoji, false),
+        Command::Nick(new) => {
+            let (old, addr) = curr_user.get_mut().clone()?;
+            let new = match state.config.nick.validate(&new) {
+                Ok(new) => new,
+                Err(e) => return Some(Response::Error(e)),
+            };
+            let (old_key, new_key) = (nick::key(&old), nick::key(&new));
+
+            // Everything happens under the users lock, so nobody can take either name meanwhile
+            let mut users = state.users.lock().unwrap();
+            let mut storage = state.storage.lock().unwrap();
+            if let Some(ban) = stored(storage.bans()).nick(&new, chat_server::now()) {
+                return Some(Response::Error(ChatError::Banned(ban.expires)));
+            }
+            // Someone else's account is theirs even while they are offline
+            if old_key != new_key && (users.contains_key(&new_key) || stored(storage.account(&new_key)).is_some()) {
+                return Some(Response::Error(ChatError::NickTaken));
+            }
+            let mut user = users.remove(&old_key)?;
+            user.name = new.clone();
+            users.insert(new_key.clone(), user);
+
+            // Our own lists, queued messages and history come along, and we stay in everyone
+            // else's lists and reactions under the new name
+            if let Err(e) = storage.rename_account(&old_key, &new) {
+                error!(error = %e, "could not rename account");
+            }
+            // Changing names doesn't get anyone out of a mute, or refill their rate limits
+            let mut mutes = state.mutes.lock().unwrap();
+            if let Some(until) = mutes.remove(&old_key) {
+                mutes.insert(new_key.clone(), until);
+            }
+            state.account_limits.lock().unwrap().rename(&old_key, new_key.clone());
+
+            curr_user.set(Some((new.clone(), addr)));
+            // Nobody is told about an invisible user, their contacts find out once they show up
+            let invisible = users.get(&new_key).is_some_and(|user| user.presence == Presence::Invisible);
+            if invisible { return Some(Response::Nick(new)); }
+            // Only those who know the user are told: whoever has them as a contact, their own
+            // contacts and everyone they have exchanged messages with
+            let mut peers = stored(storage.listed_by(List::Contacts, &new_key));
+            peers.extend(stored(storage.list(List::Contacts, &new_key)).iter().map(|name| nick::key(name)));
+            for record in stored(storage.user_messages(&new_key)) {
+                peers.push(nick::key(&record.from));
+                peers.push(nick::key(&record.to));
+            }
+            peers.sort();
+            peers.dedup();
+            let blocked = stored(storage.list(List::Blocks, &new_key));
+            let told = peers.iter()
+                .filter(|key| **key != new_key && !blocked.iter().any(|b| nick::key(b) == **key))
+                .filter_map(|key| users.get(key));
+            for user in told {
+                let _ = user.outbox.send(Some(Response::Renamed(old.clone(), new.clone())));
+            }
+            Some(Response::Nick(new))
+        },
+        Command::Status(presence, status) => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            let mut users = state.users.lock().unwrap();
+            let user = users.get_mut(&nick::key(name))?;
+            let before = (user.presence.shown(), user.status.clone());
+            // Offline is the server's to decide, asking for it means not being seen
+            user.presence = if presence == Presence::Offline { Presence::Invisible } else { presence };
+            user.status = status.chars().take(MAX_STATUS_LEN).collect();
+            let res = Response::Status(user.presence, user.status.clone());
+
+            let (name, presence) = (user.name.clone(), user.presence.shown());
+            let status = if presence == Presence::Offline { String::new() } else { user.status.clone() };
+            if before != (presence, status.clone()) {
+                notify_contacts(state, &users, &name, presence, &status);
+            }
+            Some(res)
+        },
+        Command::AddContact(contact) => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            let contact = match state.config.nick.validate(&contact) {
+                Ok(contact) => contact,
+                Err(e) => return Some(Response::Error(e)),
+            };
+            let users = state.users.lock().unwrap();
+            let list = update_list(state, List::Contacts, name, |list| {
+                if !list.iter().any(|c| nick::key(c) == nick::key(&contact)) {
+                    list.push(contact);
+                }
+            });
+            Some(Response::Contacts(contact_list(state, &users, name, &list)))
+        },
+        Command::RemoveContact(contact) => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            let users = state.users.lock().unwrap();
+            let list = update_list(state, List::Contacts, name, |list| {
+                list.retain(|c| nick::key(c) != nick::key(&contact));
+            });
+            Some(Response::Contacts(contact_list(state, &users, name, &list)))
+        },
+        Command::Contacts => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            let users = state.users.lock().unwrap();
+            let list = stored(state.storage.lock().unwrap().list(List::Contacts, &nick::key(name)));
+            Some(Response::Contacts(contact_list(state, &users, name, &list)))
+        },
+        Command::Block(blocked) => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            let blocked = match state.config.nick.validate(&blocked) {
+                Ok(blocked) => blocked,
+                Err(e) => return Some(Response::Error(e)),
+            };
+            Some(Response::Blocked(update_list(state, List::Blocks, name, |list| {
+                if !list.iter().any(|b| nick::key(b) == nick::key(&blocked)) {
+                    list.push(blocked);
+                }
+            })))
+        },
+        Command::Unblock(blocked) => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            Some(Response::Blocked(update_list(state, List::Blocks, name, |list| {
+                list.retain(|b| nick::key(b) != nick::key(&blocked));
+            })))
+        },
+        Command::Blocked => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            Some(Response::Blocked(stored(state.storage.lock().unwrap().list(List::Blocks, &nick::key(name)))))
+        },
+        Command::Oper(password) => {
+            let (name, _) = curr_user.get_mut().as_ref()?;
+            let key = nick::key(name);
+            let allowed = state.config.operators.iter()
+                .any(|(operator, expected)| nick::key(operator) == key && same_secret(expected, &password));
+            if !allowed { return Some(Response::Error(ChatError::NotOperator)); }
+            state.users.lock().unwrap().get_mut(&key)?.operator = true;
+            info!("became operator");
+            Some(Response::Ok)
+        },
+        Command::Grant(name) => {
+            let mut users = state.users.lock().unwrap();
+            if !is_operator(&users, session, curr_user.get_mut()) { return Some(Response::Error(ChatError::NotOperator)); }
+            match users.get_mut(&nick::key(&name)) {
+                Some(user) => {
+                    user.operator = true;
+                    Some(Response::Ok)
+                },
+                None => Some(Response::Error(ChatError::NoSuchUser)),
+            }
+        },
+        Command::Kick(name) => {
+            let users = state.users.lock().unwrap();
+            if !is_operator(&users, session, curr_user.get_mut()) { return Some(Response::Error(ChatError::NotOperator)); }
+            let me = actor(session, curr_user.get_mut());
+            if kick(&users, &name, format!("kicked by {}", me)) { Some(Response::Ok) }
+            else { Some(Response::Error(ChatError::NoSuchUser)) }
+        },
+        Command::Ban(target, duration) => {
+            let users = state.users.lock().unwrap();
+            if !is_operator(&users, session, curr_user.get_mut()) { return Some(Response::Error(ChatError::NotOperator)); }
+            let me = actor(session, curr_user.get_mut());
+            Some(Response::Bans(ban(state, &users, target, duration, &me)))
+        },
+        Command::Unban(target) => {
+            if !is_operator(&state.users.lock().unwrap(), session, curr_user.get_mut()) {
+                return Some(Response::Error(ChatError::NotOperator));
+            }
+            let mut storage = state.storage.lock().unwrap();
+            if let Err(e) = storage.remove_ban(&target) {
+                error!(error = %e, "could not save bans");
+            }
+            info!(?target, "unban");
+            Some(Response::Bans(stored(storage.bans()).active(chat_server::now())))
+        },
+        Command::Bans => {
+            if !is_operator(&state.users.lock().unwrap(), session, curr_user.get_mut()) {
+                return Some(Response::Error(ChatError::NotOperator));
+            }
+            Some(Response::Bans(stored(state.storage.lock().unwrap().bans()).active(chat_server::now())))
+        },
+        Command::Mute(name, duration) => {
+            if !is_operator(&state.users.lock().unwrap(), session, curr_user.get_mut()) {
+                return Some(Response::Error(ChatError::NotOperator));
+            }
+            let until = duration.map(|secs| chat_server::now().saturating_add(secs.saturating_mul(1000)));
+            state.mutes.lock().unwrap().insert(nick::key(&name), until);
+            info!(user = %name, ?until, "mute");
+            Some(Response::Ok)
+        },
+        Command::Unmute(name) => {
+            if !is_operator(&state.users.lock().unwrap(), session, curr_user.get_mut()) {
+                return Some(Response::Error(ChatError::NotOperator));
+            }
+            state.mutes.lock().unwrap().remove(&nick::key(&name));
+            Some(Response::Ok)
+        },
+        Command::Connections => {
+            if !is_operator(&state.users.lock().unwrap(), session, curr_user.get_mut()) {
+                return Some(Response::Error(ChatError::NotOperator));
+            }
+            let connections = state.connections.lock().unwrap();
+            let mut per_ip: Vec<(IpAddr, u64)> = connections.per_ip.iter()
+                .map(|(ip, n)| (*ip, *n as u64))
+                .collect();
+            per_ip.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            Some(Response::Connections(connections.total as u64, per_ip))
+        },
+        Command::Admin(token) => {
+            let token_ok = !state.config.admin_token.is_empty() && same_secret(&token, &state.config.admin_token);
+            if !token_ok {
+                warn!("wrong admin token");
+                return Some(Response::Error(ChatError::BadToken));
+            }
+            session.admin.store(true, Ordering::Relaxed);
+            info!("admin connection");
+            Some(Response::Ok)
+        },
+        Command::Sessions => {
+            if !session.admin.load(Ordering::Relaxed) { return Some(Response::Error(ChatError::NotOperator)); }
+            let users = state.users.lock().unwrap();
+            let mut sessions: Vec<SessionInfo> = users.values().map(User::session).collect();
+            sessions.sort_by(|a, b| a.name.cmp(&b.name));
+            Some(Response::Sessions(sessions))
+        },
+        Command::Broadcast(msg) => {
+            if !session.admin.load(Ordering::Relaxed) { return Some(Response::Error(ChatError::NotOperator)); }
+            broadcast(&state.users.lock().unwrap(), &msg);
+            Some(Response::Ok)
+        },
+        Command::Stats => {
+            if !session.admin.load(Ordering::Relaxed) { return Some(Response::Error(ChatError::NotOperator)); }
+            let stats = state.metrics.counters().iter()
+                .chain(gauges(state).iter())
+                .map(|(name, _, value)| (name.to_string(), *value))
+                .collect();
+            Some(Response::Stats(stats))
+        },
+        Command::Export(name) => {
+            let key = nick::key(&name);
+            let own = curr_user.get_mut().as_ref().is_some_and(|(me, _)| nick::key(me) == key);
+            if !own && !is_operator(&state.users.lock().unwrap(), session, curr_user.get_mut()) {
+                return Some(Response::Error(ChatError::NotOperator));
+            }
+            let mut records = stored(state.storage.lock().unwrap().user_messages(&key));
+            let now = chat_server::now();
+            records.retain(|record| !record.expired(now));
+            Some(Response::History(records))
+        },
+        Command::Import(records) => {
+            if !session.admin.load(Ordering::Relaxed) { return Some(Response::Error(ChatError::NotOperator)); }
+            let mut storage = state.storage.lock().unwrap();
+            let now = chat_server::now();
+            for record in records.iter().filter(|record| !record.expired(now)) {
+                if let Err(e) = storage.add_message(record) {
+                    error!(error = %e, "could not import message");
+                    return Some(Response::Error(ChatError::Storage));
+                }
+            }
+            info!(messages = records.len(), "imported messages");
+            Some(Response::Ok)
+        },
+    }
+}
+
+fn gauges(state: &State) -> [(&'static str, &'static str, u64); 2] {
+    let open = state.connections.lock().unwrap().total as u64;
+    let online = state.users.lock().unwrap().len() as u64;
+    [
+        ("chat_open_connections", "Connections open right now.", open),
+        ("chat_online_users", "Users logged in right now.", online),
+    ]
+}
+
+async fn serve_metrics(listener: TcpListener, state: Arc<State>) {
+    loop {
+        let mut socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                warn!(error = %e, "could not accept metrics connection");
+                continue;
+            },
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let reply = match http::read_request(&mut socket).await {
+                Ok(Some(req)) if req.method == "GET" && req.path == "/metrics" => {
+                    Reply::text(200, state.metrics.render(&gauges(&state)))
+                },
+                Ok(Some(_)) => Reply::text(404, "not found\n".to_string()),
+                Ok(None) => return,
+                Err(e) => Reply::text(400, format!("{}\n", e)),
+            };
+            if let Err(e) = http::write_reply(&mut socket, &reply).await {
+                debug!(error = %e, "could not write metrics");
+            }
+        });
+    }
+}
+
+// Local HTTP API for running the server without being logged in as an operator
+async fn serve_admin(listener: TcpListener, state: Arc<State>) {
+    loop {
+        let (mut socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(error = %e, "could not accept admin connection");
+                continue;
+            },
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let reply = match http::read_request(&mut socket).await {
+                Ok(Some(req)) => {
+                    let mut fields = vec![("method", req.method.as_str().into()), ("path", req.path.as_str().into())];
+                    let reply = match admin_body(&state, &req) {
+                        Ok(body) => {
+                            fields.extend(admin_audit_fields(&req, &body));
+                            admin_request(&state, &req, &body)
+                        },
+                        Err(reply) => reply,
+                    };
+                    fields.push(("status", (reply.status as u64).into()));
+                    audit(&state, "admin_api", Some(peer), fields);
+                    reply
+                },
+                Ok(None) => return,
+                Err(e) => admin_error(400, &e.to_string()),
+            };
+            if let Err(e) = http::write_reply(&mut socket, &reply).await {
+                debug!(error = %e, "could not write admin reply");
+            }
+        });
+    }
+}
+
+// The request's body, once its token checks out. Nothing else about a request without the
+// right token is looked at, let alone parsed or written to the audit log.
+fn admin_body(state: &State, req: &Request) -> Result<Value, Reply> {
+    let token = req.header("authorization").and_then(|value| value.strip_prefix("Bearer "));
+    if !token.is_some_and(|token| same_secret(token, &state.config.admin_token)) {
+        return Err(admin_error(401, "missing or wrong token"));
     }
+    if req.body.is_empty() { return Ok(Value::Null); }
+    std::str::from_utf8(&req.body).ok()
+        .and_then(Value::parse)
+        .ok_or_else(|| admin_error(400, "body is not json"))
 }
 
-async fn response(socket: &mut TcpStream, res: Option<Response>) -> Result<(), Box<dyn Error>> {
+fn admin_request(state: &State, req: &Request, body: &Value) -> Reply {
+    let field = |name: &str| body.get(name).and_then(Value::as_str).map(str::to_string);
+    info!(method = %req.method, path = %req.path, "admin request");
+
+    let ok = || Reply::json(200, Value::object([("ok", Value::Bool(true))]).to_string());
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/sessions") => {
+            let users = state.users.lock().unwrap();
+            let mut sessions: Vec<&User> = users.values().collect();
+            sessions.sort_by(|a, b| a.name.cmp(&b.name));
+            Reply::json(200, Value::Array(sessions.into_iter().map(session_json).collect()).to_string())
+        },
+        ("GET", path) if path.starts_with("/sessions/") => {
+            let name = match http::percent_decode(&path["/sessions/".len()..]) {
+                Some(name) => name,
+                None => return admin_error(400, "bad nick"),
+            };
+            match state.users.lock().unwrap().get(&nick::key(&name)) {
+                Some(user) => Reply::json(200, session_json(user).to_string()),
+                None => admin_error(404, "no such user is logged in"),
+            }
+        },
+        ("POST", "/kick") => {
+            let name = match field("nick") {
+                Some(name) => name,
+                None => return admin_error(400, "missing nick"),
+            };
+            let reason = field("reason").unwrap_or_else(|| "kicked by the server".to_string());
+            if kick(&state.users.lock().unwrap(), &name, reason) { ok() }
+            else { admin_error(404, "no such user is logged in") }
+        },
+        ("POST", "/ban") => {
+            let target = match (field("nick"), field("ip")) {
+                (Some(name), None) => BanTarget::Nick(name),
+                (None, Some(ip)) => match ip.parse() {
+                    Ok(range) => BanTarget::Ip(range),
+                    Err(()) => return admin_error(400, "bad ip range"),
+                },
+                _ => return admin_error(400, "need one of nick or ip"),
+            };
+            let duration = body.get("seconds").and_then(Value::as_u64);
+            ban(state, &state.users.lock().unwrap(), target, duration, "the server");
+            ok()
+        },
+        ("POST", "/broadcast") => {
+            let msg = match field("message") {
+                Some(msg) => msg,
+                None => return admin_error(400, "missing message"),
+            };
+            broadcast(&state.users.lock().unwrap(), &msg);
+            ok()
+        },
+        (_, "/sessions") | (_, "/kick") | (_, "/ban") | (_, "/broadcast") => admin_error(405, "wrong method"),
+        _ => admin_error(404, "not found"),
+    }
+}
+
+// What `audit_fields` keeps for the same commands coming in over the chat protocol
+fn admin_audit_fields(req: &Request, body: &Value) -> Vec<(&'static str, Value)> {
+    let field = |name: &str| body.get(name).cloned().unwrap_or(Value::Null);
+    match (req.method.as_str(), req.path.as_str()) {
+        ("POST", "/kick") => vec![("target", field("nick"))],
+        ("POST", "/ban") => {
+            let target = match (body.get("nick"), body.get("ip")) {
+                (Some(name), _) => Value::object([("nick", name.clone())]),
+                (None, Some(ip)) => Value::object([("ip", ip.clone())]),
+                (None, None) => Value::Null,
+            };
+            vec![("target", target), ("seconds", field("seconds"))]
+        },
+        ("POST", "/broadcast") => vec![("message", field("message"))],
+        _ => Vec::new(),
+    }
+}
+
+fn session_json(user: &User) -> Value {
+    Value::object([
+        ("nick", user.name.as_str().into()),
+        ("peer", user.peer.to_string().into()),
+        ("addr", user.addr.to_string().into()),
+        ("presence", format!("{:?}", user.presence).to_lowercase().into()),
+        ("status", user.status.as_str().into()),
+        ("operator", user.operator.into()),
+        ("logged_in", user.logged_in.into()),
+        ("commands", user.commands.into()),
+        ("messages_sent", user.messages_sent.into()),
+    ])
+}
+
+fn admin_error(status: u16, msg: &str) -> Reply {
+    Reply::json(status, Value::object([("error", msg.into())]).to_string())
+}
+
+// Takes as long for every wrong token of the right length, so it can't be guessed a byte at a time
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn init_logging(config: &Config) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log_level));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
+
+// Disconnects the user if they are online, returns whether they were
+fn kick(users: &Users, name: &str, reason: String) -> bool {
+    match users.get(&nick::key(name)) {
+        Some(user) => {
+            info!(user = %user.name, %reason, "kick");
+            user.kick(reason);
+            true
+        },
+        None => false,
+    }
+}
+
+// Bans the target for `duration` seconds or for good, and kicks anyone online it matches.
+// Returns the bans now in effect.
+fn ban(state: &State, users: &Users, target: BanTarget, duration: Option<u64>, by: &str) -> Vec<Ban> {
+    let now = chat_server::now();
+    let expires = duration.map(|secs| now.saturating_add(secs.saturating_mul(1000)));
+    let mut storage = state.storage.lock().unwrap();
+    if let Err(e) = storage.add_ban(&Ban { target: target.clone(), expires }) {
+        error!(error = %e, "could not save bans");
+    }
+    info!(?target, ?expires, by, "ban");
+    let banned = users.iter().filter(|(key, user)| match &target {
+        BanTarget::Nick(name) => nick::key(name) == **key,
+        BanTarget::Ip(range) => range.contains(user.peer.ip()),
+    });
+    for (_, user) in banned {
+        user.kick(format!("banned by {}", by));
+    }
+    stored(storage.bans()).active(now)
+}
+
+// Deletes the messages that have expired or are older than the retention allows, every
+// `sweep_interval` seconds
+async fn sweep(state: Arc<State>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.sweep_interval.max(1)));
+    loop {
+        interval.tick().await;
+        let now = chat_server::now();
+        let sent_before = match state.config.retention.direct {
+            0 => None,
+            secs => Some(now.saturating_sub(secs.saturating_mul(1000))),
+        };
+        match state.storage.lock().unwrap().expire(now, sent_before) {
+            Ok(0) => (),
+            Ok(removed) => info!(removed, "expired messages"),
+            Err(e) => error!(error = %e, "could not expire messages"),
+        }
+    }
+}
+
+fn broadcast(users: &Users, msg: &str) {
+    info!(msg, "broadcast");
+    for user in users.values() {
+        let _ = user.outbox.send(Some(Response::Broadcast(msg.to_string())));
+    }
+}
+
+// Removes a user from the online users
+fn leave(state: &State, name: &str) {
+    let mut users = state.users.lock().unwrap();
+    if let Some(user) = users.remove(&nick::key(name)) {
+        let mut storage = state.storage.lock().unwrap();
+        if let Some(mut account) = stored(storage.account(&nick::key(name))) {
+            account.last_seen = chat_server::now();
+            if let Err(e) = storage.put_account(&account) {
+                error!(error = %e, "could not save account");
+            }
+        }
+        drop(storage);
+        if user.presence != Presence::Invisible {
+            notify_contacts(state, &users, &user.name, Presence::Offline, "");
+        }
+    }
+}
+
+// Pushes a presence change to everyone online who has `name` in their contact list
+fn notify_contacts(state: &State, users: &Users, name: &str, presence: Presence, status: &str) {
+    let key = nick::key(name);
+    let storage = state.storage.lock().unwrap();
+    for owner in stored(storage.listed_by(List::Contacts, &key)) {
+        if is_blocked(&**storage, &key, &owner) { continue; }
+        if let Some(user) = users.get(&owner) {
+            let _ = user.outbox.send(Some(Response::PresenceChanged(name.to_string(), presence, status.to_string())));
+        }
+    }
+}
+
+// How the contacts in `owner`'s `list` look to them right now
+fn contact_list(state: &State, users: &Users, owner: &str, list: &[String]) -> Vec<(String, Presence, String)> {
+    let owner = nick::key(owner);
+    let storage = state.storage.lock().unwrap();
+    list.iter()
+        .map(|contact| match users.get(&nick::key(contact)) {
+            Some(user) if user.presence != Presence::Invisible && !is_blocked(&**storage, &nick::key(contact), &owner) => {
+                (user.name.clone(), user.presence, user.status.clone())
+            },
+            _ => (contact.clone(), Presence::Offline, String::new()),
+        })
+        .collect()
+}
+
+// Whether the user with key `owner` has blocked the one with key `other`
+fn is_blocked(storage: &dyn Storage, owner: &str, other: &str) -> bool {
+    stored(storage.list(List::Blocks, owner)).iter().any(|b| nick::key(b) == other)
+}
+
+// Changes `owner`'s list and saves it, returns the list as it is now
+fn update_list<F: FnOnce(&mut Vec<String>)>(state: &State, list: List, owner: &str, change: F) -> Vec<String> {
+    let key = nick::key(owner);
+    let mut storage = state.storage.lock().unwrap();
+    let mut names = stored(storage.list(list, &key));
+    change(&mut names);
+    if let Err(e) = storage.set_list(list, &key, &names) {
+        error!(error = %e, "could not save list");
+    }
+    names
+}
+
+// Storage errors are logged and otherwise taken as there being nothing stored, so a failing
+// disk makes the server forgetful rather than taking it down
+fn stored<T: Default>(result: io::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        error!(error = %e, "could not read from storage");
+        T::default()
+    })
+}
+
+// Whether the logged in user, if any, is an operator
+fn is_operator(users: &Users, session: &Session, curr_user: &Option<(String, SocketAddr)>) -> bool {
+    session.admin.load(Ordering::Relaxed)
+        || curr_user.as_ref().is_some_and(|(name, _)| users.get(&nick::key(name)).is_some_and(|user| user.operator))
+}
+
+// Looks up a message to edit or delete, which only its author and operators can do. Anyone
+// outside the conversation isn't told whether it exists.
+fn changeable(state: &State, session: &Session, curr_user: &Option<(String, SocketAddr)>, id: u64) -> Result<Record, ChatError> {
+    let operator = is_operator(&state.users.lock().unwrap(), session, curr_user);
+    let me = curr_user.as_ref().map(|(name, _)| nick::key(name));
+    let now = chat_server::now();
+    match stored(state.storage.lock().unwrap().message(id)).filter(|record| !record.expired(now)) {
+        Some(record) if operator || me == Some(nick::key(&record.from)) => Ok(record),
+        Some(record) if me == Some(nick::key(&record.to)) => Err(ChatError::NotOperator),
+        _ => Err(ChatError::NoSuchMessage),
+    }
+}
+
+// Adds or takes back a reaction, which only the two ends of the conversation can do. The other
+// end is sent every reaction to the message whenever they change.
+fn react(state: &State, curr_user: &Option<(String, SocketAddr)>, id: u64, emoji: &str, add: bool) -> Option<Response> {
+    let (name, _) = curr_user.as_ref()?;
+    let key = nick::key(name);
+    // Emoji can be several characters, but never letters, digits or spaces
+    let count = emoji.chars().count();
+    if add && (count == 0 || count > MAX_REACTION_LEN || emoji.chars().any(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_control())) {
+        return Some(Response::Error(ChatError::InvalidReaction));
+    }
+    let mut storage = state.storage.lock().unwrap();
+    let now = chat_server::now();
+    let record = match stored(storage.message(id)).filter(|record| !record.expired(now)) {
+        Some(record) if nick::key(&record.from) == key || nick::key(&record.to) == key => record,
+        _ => return Some(Response::Error(ChatError::NoSuchMessage)),
+    };
+    if add {
+        let reactions = stored(storage.reactions(id));
+        if reactions.len() >= MAX_REACTIONS && !reactions.iter().any(|(e, _)| e == emoji) {
+            return Some(Response::Error(ChatError::TooManyReactions));
+        }
+    }
+    let changed = if add { storage.react(id, emoji, name) } else { storage.unreact(id, emoji, &key) };
+    match changed {
+        Ok(true) => (),
+        Ok(false) => return Some(Response::Ok),
+        Err(e) => {
+            error!(error = %e, "could not save reaction");
+            return Some(Response::Error(ChatError::Storage));
+        },
+    }
+    let reactions = stored(storage.reactions(id));
+    // The users lock comes first
+    drop(storage);
+    notify_conversation(state, &record, Some(&key), || Response::Reactions(id, reactions.clone()));
+    Some(Response::Ok)
+}
+
+// Pushes the event to both ends of the conversation the record is from, apart from `actor`
+// who gets an answer instead
+fn notify_conversation<F: Fn() -> Response>(state: &State, record: &Record, actor: Option<&str>, event: F) {
+    let users = state.users.lock().unwrap();
+    let mut keys = vec![nick::key(&record.from), nick::key(&record.to)];
+    keys.dedup();
+    for key in keys.iter().filter(|key| Some(key.as_str()) != actor) {
+        if let Some(user) = users.get(key) {
+            let _ = user.outbox.send(Some(event()));
+        }
+    }
+}
+
+// Who to name as having kicked or banned someone
+fn actor(session: &Session, curr_user: &Option<(String, SocketAddr)>) -> String {
+    match curr_user {
+        Some((name, _)) => name.clone(),
+        None if session.admin.load(Ordering::Relaxed) => "the server".to_string(),
+        None => String::new(),
+    }
+}
+
+fn is_muted(mutes: &Mutes, key: &str) -> bool {
+    mutes.get(key).is_some_and(|until| until.is_none_or(|until| until > chat_server::now()))
+}
+
+async fn response(socket: &mut OwnedWriteHalf, res: Option<Response>) -> Result<(), Box<dyn Error>> {
     if let Some(Response::Exit) = res { return Ok(()); }
-    socket.writable().await?;
     let bytes = Packet::to_byte_vec(res.serialize());
     socket.write_all(&bytes).await?;
     socket.write_all(&[0, 0, 0, 0]).await?;  // Unessecary extra sys call
@@ -108,13 +1282,11 @@ async fn response(socket: &mut TcpStream, res: Option<Response>) -> Result<(), B
 }
 
 // NOTE: DRY CODE!!
-async fn request(socket: &mut TcpStream) -> Result<Vec<Packet>, Box<dyn Error>> {
-    socket.readable().await?;
+async fn request(socket: &mut OwnedReadHalf) -> Result<Vec<Packet>, Box<dyn Error>> {
     let mut bytes = Vec::new();
     loop {
         let amount = match socket.read_u32().await {
             Ok(0) => break,
-            Ok(n) if n == 0 => break,
             Ok(n) => n,
             // Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break, // wut is this?
             Err(e)     => return Err(Box::new(e)),
@@ -135,9 +1307,308 @@ async fn request(socket: &mut TcpStream) -> Result<Vec<Packet>, Box<dyn Error>>
 }
 
 fn get_buffer(amount: u32) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(amount as usize);
-    for _ in 0..amount {
-        buf.push(0);
+    vec![0; amount as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_server::storage::MemoryStorage;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    fn state() -> State {
+        let config = Config { audit_file: String::new(), ..Config::default() };
+        State {
+            users: Mutex::new(HashMap::new()),
+            storage: Mutex::new(Box::new(MemoryStorage::default())),
+            mutes: Mutex::new(HashMap::new()),
+            account_limits: Mutex::new(Limiter::new(config.rate_limit)),
+            ip_limits: Mutex::new(Limiter::new(config.ip_rate_limit)),
+            connections: Mutex::new(Connections::default()),
+            metrics: Metrics::default(),
+            audit: Mutex::new(Audit::open(&config.audit_file).unwrap()),
+            config,
+        }
+    }
+
+    // One end of a connection, with what the server pushed to it
+    struct Client {
+        session: Session,
+        curr_user: Cell<Option<(String, SocketAddr)>>,
+        pushed: mpsc::UnboundedReceiver<Option<Response>>,
+    }
+
+    impl Client {
+        fn run(&mut self, state: &State, command: Command) -> Option<Response> {
+            handle_command(command, state, &self.session, &mut self.curr_user)
+        }
+
+        // Whatever is waiting in the outbox, without waiting for more
+        fn pushed(&mut self) -> Vec<Response> {
+            let mut cx = Context::from_waker(Waker::noop());
+            let mut pushed = Vec::new();
+            while let Poll::Ready(Some(res)) = pin!(self.pushed.recv()).poll(&mut cx) {
+                pushed.extend(res);
+            }
+            pushed
+        }
+    }
+
+    fn connect(peer: &str) -> Client {
+        let (outbox, pushed) = mpsc::unbounded_channel();
+        let session = Session { peer: peer.parse().unwrap(), admin: AtomicBool::new(false), outbox, kick: Arc::new(Notify::new()) };
+        Client { session, curr_user: Cell::new(None), pushed }
+    }
+
+    // Logs `name` in with an account, the way `Command::Login` would without the password
+    fn log_in(state: &State, name: &str) -> Client {
+        log_in_from(state, name, "10.0.0.1:5000")
+    }
+
+    fn log_in_from(state: &State, name: &str, peer: &str) -> Client {
+        let client = connect(peer);
+        let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        state.users.lock().unwrap().insert(nick::key(name), User {
+            name: name.to_string(),
+            addr,
+            peer: client.session.peer,
+            outbox: client.session.outbox.clone(),
+            kick: client.session.kick.clone(),
+            presence: Presence::Online,
+            status: String::new(),
+            operator: false,
+            logged_in: 0,
+            commands: 0,
+            messages_sent: 0,
+        });
+        let account = Account { name: name.to_string(), created: 0, last_seen: 0, secret: None };
+        state.storage.lock().unwrap().put_account(&account).unwrap();
+        client.curr_user.set(Some((name.to_string(), addr)));
+        client
+    }
+
+    fn message(to: &str, id: u64, msg: &str) -> Command {
+        Command::Message(to.to_string(), id, msg.to_string(), None)
+    }
+
+    #[test]
+    fn a_retry_to_someone_who_went_offline_is_queued() {
+        let state = state();
+        let mut alice = log_in(&state, "alice");
+        let _bob = log_in(&state, "bob");
+        assert!(matches!(alice.run(&state, message("bob", 1, "hi")), Some(Response::Message(_, 1, ..))));
+
+        leave(&state, "bob");
+        assert!(matches!(alice.run(&state, message("bob", 1, "hi")), Some(Response::Queued(1))));
+        let mut bob = log_in(&state, "bob");
+        match bob.run(&state, Command::Show) {
+            Some(Response::Unread(senders)) => {
+                assert_eq!(senders.len(), 1);
+                assert_eq!(senders[0].1.iter().map(|record| record.id).collect::<Vec<_>>(), [1]);
+            },
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn a_queued_message_is_not_delivered_again_on_retry() {
+        let state = state();
+        let mut alice = log_in(&state, "alice");
+        state.storage.lock().unwrap().put_account(&Account { name: "bob".to_string(), created: 0, last_seen: 0, secret: None }).unwrap();
+        assert!(matches!(alice.run(&state, message("bob", 1, "hi")), Some(Response::Queued(1))));
+        assert!(matches!(alice.run(&state, message("bob", 1, "hi")), Some(Response::Queued(1))));
+
+        // Once bob is back the message is still only waiting for him in the queue, and once
+        let mut bob = log_in(&state, "bob");
+        assert!(matches!(alice.run(&state, message("bob", 1, "hi")), Some(Response::Queued(1))));
+        match bob.run(&state, Command::Show) {
+            Some(Response::Unread(senders)) => assert_eq!(senders[0].1.len(), 1),
+            res => panic!("{:?}", res),
+        }
+        // Taken out of the queue it can be delivered directly
+        assert!(matches!(alice.run(&state, message("bob", 1, "hi")), Some(Response::Message(_, 1, ..))));
+    }
+
+    #[test]
+    fn a_retry_gets_the_stored_text() {
+        let state = state();
+        let mut alice = log_in(&state, "alice");
+        let _bob = log_in(&state, "bob");
+        let mut carol = log_in(&state, "carol");
+        alice.run(&state, message("bob", 1, "hi"));
+        match alice.run(&state, message("bob", 1, "changed")) {
+            Some(Response::Message(_, 1, _, msg, ..)) => assert_eq!(msg, "hi"),
+            res => panic!("{:?}", res),
+        }
+        // Someone else's id doesn't give anything away
+        assert!(matches!(carol.run(&state, message("bob", 1, "hi")), Some(Response::Failed(1, _))));
+    }
+
+    fn renamed(client: &mut Client) -> Vec<(String, String)> {
+        client.pushed().into_iter()
+            .filter_map(|res| match res {
+                Response::Renamed(old, new) => Some((old, new)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_peers_hear_about_a_rename() {
+        let state = state();
+        let mut alice = log_in(&state, "alice");
+        let mut contact = log_in(&state, "bob");
+        let mut talked_to = log_in(&state, "carol");
+        let mut stranger = log_in(&state, "dave");
+        let mut blocked = log_in(&state, "erin");
+        contact.run(&state, Command::AddContact("alice".to_string()));
+        alice.run(&state, message("carol", 1, "hi"));
+        alice.run(&state, message("erin", 2, "hi"));
+        alice.run(&state, Command::Block("erin".to_string()));
+        for client in [&mut contact, &mut talked_to, &mut stranger, &mut blocked] {
+            client.pushed();
+        }
+
+        assert!(matches!(alice.run(&state, Command::Nick("Alicia".to_string())), Some(Response::Nick(name)) if name == "Alicia"));
+        let told = [("alice".to_string(), "Alicia".to_string())];
+        assert_eq!(renamed(&mut contact), told);
+        assert_eq!(renamed(&mut talked_to), told);
+        assert!(renamed(&mut stranger).is_empty());
+        assert!(renamed(&mut blocked).is_empty());
+    }
+
+    #[test]
+    fn a_rename_keeps_history_and_takes_no_accounts() {
+        let state = state();
+        let mut alice = log_in(&state, "alice");
+        let bob = log_in(&state, "bob");
+        alice.run(&state, message("bob", 1, "hi"));
+        assert!(matches!(alice.run(&state, Command::Nick("bob".to_string())), Some(Response::Error(ChatError::NickTaken))));
+        leave(&state, "bob");
+        bob.curr_user.set(None);
+        // Bob's account is still his while he is offline
+        assert!(matches!(alice.run(&state, Command::Nick("BOB".to_string())), Some(Response::Error(ChatError::NickTaken))));
+
+        alice.run(&state, Command::Nick("alicia".to_string()));
+        match alice.run(&state, Command::History { peer: "bob".to_string(), before: None, limit: 10 }) {
+            Some(Response::History(records)) => assert_eq!((records.len(), records[0].from.as_str()), (1, "alicia")),
+            res => panic!("{:?}", res),
+        }
+        assert!(state.users.lock().unwrap().contains_key("alicia"));
+        assert!(stored(state.storage.lock().unwrap().account("alice")).is_none());
+    }
+
+    fn search(client: &mut Client, state: &State, mode: SearchMode, pattern: &str) -> Option<Vec<String>> {
+        let page = search::Page { offset: 0, limit: 10 };
+        match client.run(state, Command::Search(mode, pattern.to_string(), page))? {
+            Response::Search(users, _) => Some(users.into_iter().map(|user| user.name).collect()),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn search_leaves_out_the_invisible_and_those_who_blocked_us() {
+        let state = state();
+        let mut alice = log_in(&state, "alice");
+        let _alina = log_in(&state, "alina");
+        let mut hiding = log_in(&state, "alix");
+        let mut blocking = log_in(&state, "albert");
+        hiding.run(&state, Command::Status(Presence::Invisible, String::new()));
+        blocking.run(&state, Command::Block("alice".to_string()));
+
+        assert_eq!(search(&mut alice, &state, SearchMode::Prefix, "AL").unwrap(), ["alice", "alina"]);
+        assert_eq!(search(&mut alice, &state, SearchMode::Exact, "all").unwrap(), ["alice", "alina"]);
+        assert_eq!(search(&mut hiding, &state, SearchMode::Exact, "alix").unwrap(), ["alix"]);
+        assert_eq!(search(&mut alice, &state, SearchMode::Fuzzy, "alica").unwrap(), ["alice", "alina"]);
+        // Nobody finds anyone without logging in
+        assert!(search(&mut connect("10.0.0.2:5000"), &state, SearchMode::Prefix, "al").is_none());
+    }
+
+    fn kicked(client: &mut Client) -> bool {
+        client.pushed().iter().any(|res| matches!(res, Response::Kicked(_)))
+    }
+
+    fn operator(state: &State, name: &str) -> Client {
+        let client = log_in_from(state, name, "192.168.0.1:5000");
+        state.users.lock().unwrap().get_mut(&nick::key(name)).unwrap().operator = true;
+        client
+    }
+
+    #[test]
+    fn only_operators_kick() {
+        let state = state();
+        let mut op = operator(&state, "op");
+        let mut alice = log_in(&state, "alice");
+        let mut bob = log_in(&state, "bob");
+        assert!(matches!(alice.run(&state, Command::Kick("bob".to_string())), Some(Response::Error(ChatError::NotOperator))));
+        assert!(!kicked(&mut bob));
+
+        assert!(matches!(op.run(&state, Command::Kick("BOB".to_string())), Some(Response::Ok)));
+        assert!(kicked(&mut bob));
+        assert!(!kicked(&mut alice));
+        leave(&state, "bob");
+        assert!(matches!(op.run(&state, Command::Kick("bob".to_string())), Some(Response::Error(ChatError::NoSuchUser))));
+    }
+
+    #[test]
+    fn bans_kick_whoever_they_match_and_keep_them_out() {
+        let state = state();
+        let mut op = operator(&state, "op");
+        let mut alice = log_in_from(&state, "alice", "10.1.2.3:5000");
+        let mut bob = log_in_from(&state, "bob", "172.16.0.1:5000");
+        let mut carol = log_in_from(&state, "carol", "172.16.0.2:5000");
+        assert!(matches!(bob.run(&state, Command::Ban(BanTarget::Nick("alice".to_string()), None)), Some(Response::Error(ChatError::NotOperator))));
+
+        assert!(matches!(op.run(&state, Command::Ban(BanTarget::Nick("Alice".to_string()), Some(60))), Some(Response::Bans(bans)) if bans.len() == 1));
+        assert!(kicked(&mut alice));
+        assert!(!kicked(&mut bob));
+        let login = Command::Login("alice".to_string(), "10.0.0.1:8080".parse().unwrap(), Some("hunter2".to_string()));
+        assert!(matches!(connect("10.9.9.9:5000").run(&state, login), Some(Response::Error(ChatError::Banned(Some(_))))));
+
+        let range = BanTarget::Ip("172.16.0.0/16".parse().unwrap());
+        assert!(matches!(op.run(&state, Command::Ban(range.clone(), None)), Some(Response::Bans(bans)) if bans.len() == 2));
+        assert!(kicked(&mut bob));
+        assert!(kicked(&mut carol));
+        assert!(!kicked(&mut op));
+        assert!(matches!(op.run(&state, Command::Unban(range)), Some(Response::Bans(bans)) if bans.len() == 1));
+    }
+
+    #[test]
+    fn renaming_keeps_the_account_rate_limited() {
+        let state = state();
+        let mut alice = log_in(&state, "alice");
+        let mut limits = Limiter::new(state.config.rate_limit);
+        let search = || Command::Search(SearchMode::Exact, "bob".to_string(), search::Page { offset: 0, limit: 1 });
+        while rate_limit(&state, &alice.session, &mut limits, alice.curr_user.get_mut(), &search()) == 0 {}
+
+        alice.run(&state, Command::Nick("alicia".to_string()));
+        // A new connection has buckets of its own, the account's are still empty
+        let mut fresh = Limiter::new(state.config.rate_limit);
+        assert!(rate_limit(&state, &alice.session, &mut fresh, alice.curr_user.get_mut(), &search()) > 0);
+    }
+
+    fn admin_post(path: &str, token: &str, body: &str) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: path.to_string(),
+            headers: vec![("authorization".to_string(), format!("Bearer {}", token))],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn the_admin_api_checks_the_token_before_the_body() {
+        let mut state = state();
+        state.config.admin_token = "secret".to_string();
+        let junk = "[".repeat(200_000);
+        assert!(matches!(admin_body(&state, &admin_post("/kick", "wrong", &junk)), Err(reply) if reply.status == 401));
+        assert!(matches!(admin_body(&state, &admin_post("/kick", "secret", &junk)), Err(reply) if reply.status == 400));
+
+        let req = admin_post("/kick", "secret", r#"{"nick": "bob"}"#);
+        let body = admin_body(&state, &req).unwrap();
+        assert_eq!(admin_audit_fields(&req, &body)[0].1, Value::from("bob"));
+        assert_eq!(admin_request(&state, &req, &body).status, 404);
     }
-    buf
 }
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bounds of the latency buckets, in seconds
+const BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+
+/// Counters for the whole server, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    pub connections: AtomicU64,
+    pub rejected_connections: AtomicU64,
+    pub logins: AtomicU64,
+    pub failed_logins: AtomicU64,
+    pub messages_routed: AtomicU64,
+    pub decode_errors: AtomicU64,
+    // How long each kind of command took to handle
+    commands: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe(&self, command: &'static str, took: Duration) {
+        let secs = took.as_secs_f64();
+        let mut commands = self.commands.lock().unwrap();
+        let histogram = commands.entry(command).or_default();
+        if let Some(i) = BUCKETS.iter().position(|le| secs <= *le) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    /// Name, help text and current value of every counter.
+    pub fn counters(&self) -> [(&'static str, &'static str, u64); 6] {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        [
+            ("chat_connections_total", "Connections accepted.", load(&self.connections)),
+            ("chat_rejected_connections_total", "Connections turned away for being over a limit.", load(&self.rejected_connections)),
+            ("chat_logins_total", "Successful logins.", load(&self.logins)),
+            ("chat_failed_logins_total", "Logins that were refused.", load(&self.failed_logins)),
+            ("chat_messages_routed_total", "Messages handed to their recipient or queued for them.", load(&self.messages_routed)),
+            ("chat_decode_errors_total", "Requests that could not be decoded.", load(&self.decode_errors)),
+        ]
+    }
+
+    /// Everything counted so far, plus `gauges` given as (name, help, value).
+    pub fn render(&self, gauges: &[(&str, &str, u64)]) -> String {
+        let mut out = String::new();
+        for (name, help, value) in self.counters() {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+        }
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+        }
+
+        let name = "chat_command_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time taken to handle a command.\n# TYPE {} histogram", name, name);
+        for (command, histogram) in self.commands.lock().unwrap().iter() {
+            let mut total = 0;
+            for (le, n) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                total += n;
+                let _ = writeln!(out, "{}_bucket{{command=\"{}\",le=\"{}\"}} {}", name, command, le, total);
+            }
+            let _ = writeln!(out, "{}_bucket{{command=\"{}\",le=\"+Inf\"}} {}", name, command, histogram.count);
+            let _ = writeln!(out, "{}_sum{{command=\"{}\"}} {}", name, command, histogram.sum);
+            let _ = writeln!(out, "{}_count{{command=\"{}\"}} {}", name, command, histogram.count);
+        }
+        out
+    }
+}
@@ -0,0 +1,193 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::error::Error;
+use crate::Nickname;
+
+/// What an exact `Command::Search` for everyone asks for, so nobody can be called that
+/// whatever the config reserves.
+pub const ALL: &str = "all";
+
+/// Rules a nickname has to follow before it can be used to log in.
+///
+/// Nicknames are NFKC normalized before they are checked, so compatibility forms like
+/// full width letters collapse into their plain counterparts, and two nicknames that only
+/// differ in case are the same user. Letters and digits are always allowed; with the default
+/// ascii-only policy there is nothing left that can be confused with another name. With
+/// `allow_unicode` a name has to stick to one script, give or take the ones Chinese, Japanese
+/// and Korean are written in together, and `key` folds letters that look like latin ones into
+/// them, so "аlice" with a cyrillic "а" is neither accepted nor a different user.
+#[derive(Debug, Clone)]
+pub struct NickPolicy {
+    pub min_len: usize,
+    pub max_len: usize,
+    /// Allow letters and digits outside of ascii.
+    pub allow_unicode: bool,
+    /// Punctuation allowed on top of letters and digits.
+    pub extra_chars: String,
+    /// Names nobody can take on top of `ALL`, matched the way `key` matches names.
+    pub reserved: Vec<String>,
+}
+
+impl Default for NickPolicy {
+    fn default() -> NickPolicy {
+        NickPolicy {
+            min_len: 1,
+            max_len: 32,
+            allow_unicode: false,
+            extra_chars: "-_".to_string(),
+            reserved: Vec::new(),
+        }
+    }
+}
+
+impl NickPolicy {
+    /// Normalizes `name` and checks it against the policy, returning the name to use.
+    pub fn validate(&self, name: &str) -> Result<Nickname, Error> {
+        let name: Nickname = name.nfkc().collect();
+        let len = name.chars().count();
+        if len < self.min_len { return Err(Error::NickTooShort(self.min_len as u32)); }
+        if len > self.max_len { return Err(Error::NickTooLong(self.max_len as u32)); }
+
+        if let Some(c) = name.chars().find(|c| !self.allowed(*c)) {
+            return Err(Error::NickInvalidChar(c));
+        }
+        if !single_script(&name) { return Err(Error::NickMixedScripts); }
+        if key(&name) == ALL || self.reserved.iter().any(|reserved| key(reserved) == key(&name)) {
+            return Err(Error::NickReserved);
+        }
+        Ok(name)
+    }
+
+    fn allowed(&self, c: char) -> bool {
+        if c.is_ascii_alphanumeric() || self.extra_chars.contains(c) { return true; }
+        self.allow_unicode && c.is_alphanumeric()
+    }
+}
+
+/// The form two nicknames are compared in, so lookups don't depend on case, on which unicode
+/// form the client sent or on letters from other scripts that look the same.
+pub fn key(name: &str) -> String {
+    // Going through upper case first folds the letters lower case alone misses, like "ß"
+    // into "ss", so "straße" and "STRASSE" are the same name
+    let folded: String = name.nfkc()
+        .flat_map(char::to_uppercase)
+        .flat_map(char::to_lowercase)
+        .collect();
+    folded.nfkc().map(skeleton).collect()
+}
+
+// The latin letter a lower case greek or cyrillic one can pass for, taken from the
+// confusables the unicode consortium lists. Ascii is left alone, so the keys of ascii names
+// are just their lower case.
+fn skeleton(c: char) -> char {
+    match c {
+        'а' | 'α' => 'a',
+        'в' | 'β' => 'b',
+        'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | 'ε' => 'e',
+        'һ' | 'н' => 'h',
+        'і' | 'ι' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'ӏ' => 'l',
+        'м' | 'μ' => 'm',
+        'η' => 'n',
+        'о' | 'ο' | 'σ' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'ѕ' => 's',
+        'т' | 'τ' => 't',
+        'ν' => 'v',
+        'ԝ' => 'w',
+        'х' | 'χ' => 'x',
+        'у' | 'γ' | 'υ' => 'y',
+        'ζ' => 'z',
+        c => c,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Han,
+    Kana,
+    Hangul,
+    /// Everything this doesn't tell apart, which only has to agree with itself.
+    Other,
+}
+
+// Which script a letter belongs to, near enough to catch names that mix them. Digits and
+// punctuation go with any script and have none.
+fn script(c: char) -> Option<Script> {
+    if !c.is_alphabetic() { return None; }
+    Some(match c as u32 {
+        0x0041..=0x024f | 0x1e00..=0x1eff => Script::Latin,
+        0x0370..=0x03ff | 0x1f00..=0x1fff => Script::Greek,
+        0x0400..=0x052f | 0x1c80..=0x1c8f | 0x2de0..=0x2dff | 0xa640..=0xa69f => Script::Cyrillic,
+        0x2e80..=0x2fdf | 0x3005 | 0x3007 | 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xf900..=0xfaff
+        | 0x20000..=0x3134f => Script::Han,
+        0x3040..=0x30ff | 0x31f0..=0x31ff => Script::Kana,
+        0x1100..=0x11ff | 0x3130..=0x318f | 0xa960..=0xa97f | 0xac00..=0xd7ff => Script::Hangul,
+        _ => Script::Other,
+    })
+}
+
+// One script, or latin and han together with the scripts written alongside han in Japanese
+// or Korean, which is what the "highly restrictive" profile of UTS #39 allows
+fn single_script(name: &str) -> bool {
+    let mut scripts: Vec<Script> = Vec::new();
+    for script in name.chars().filter_map(script) {
+        if !scripts.contains(&script) { scripts.push(script); }
+    }
+    let within = |allowed: &[Script]| scripts.iter().all(|script| allowed.contains(script));
+    scripts.len() <= 1
+        || within(&[Script::Latin, Script::Han, Script::Kana])
+        || within(&[Script::Latin, Script::Han, Script::Hangul])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unicode() -> NickPolicy {
+        NickPolicy { allow_unicode: true, ..NickPolicy::default() }
+    }
+
+    #[test]
+    fn all_stays_reserved_whatever_else_is() {
+        let policy = NickPolicy { reserved: vec!["admin".to_string()], ..NickPolicy::default() };
+        assert_eq!(policy.validate("ALL"), Err(Error::NickReserved));
+        assert_eq!(policy.validate("Admin"), Err(Error::NickReserved));
+        assert_eq!(NickPolicy::default().validate("all"), Err(Error::NickReserved));
+        assert_eq!(policy.validate("alley"), Ok("alley".to_string()));
+
+        let config = crate::config::Config::parse("[nick]\nreserved = admin\n").unwrap();
+        assert_eq!(config.nick.validate("all"), Err(Error::NickReserved));
+        assert_eq!(config.nick.validate("admin"), Err(Error::NickReserved));
+    }
+
+    #[test]
+    fn keys_fold_case_fully() {
+        assert_eq!(key("straße"), key("STRASSE"));
+        assert_eq!(key("Alice"), "alice");
+    }
+
+    #[test]
+    fn look_alikes_share_a_key() {
+        assert_eq!(key("аре"), key("ape"));
+        assert_eq!(key("ΑΡΕ"), key("ape"));
+        assert_ne!(key("bob"), key("dob"));
+    }
+
+    #[test]
+    fn scripts_cant_be_mixed() {
+        assert_eq!(unicode().validate("\u{430}lice"), Err(Error::NickMixedScripts));
+        assert_eq!(unicode().validate("аня"), Ok("аня".to_string()));
+        assert_eq!(unicode().validate("東京たワー"), Ok("東京たワー".to_string()));
+        assert_eq!(unicode().validate("ソウル서울"), Err(Error::NickMixedScripts));
+        assert_eq!(unicode().validate("bob2"), Ok("bob2".to_string()));
+    }
+}
@@ -0,0 +1,62 @@
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// How many rounds of PBKDF2 new passwords get. Stored hashes carry their own count, so this
+/// can go up without locking anyone out.
+pub const ITERATIONS: u32 = 100_000;
+
+/// Hashes `password` with a fresh salt, for keeping in `Account::secret`. Written as
+/// `pbkdf2-sha256$<iterations>$<salt>$<hash>` with the salt and hash in hex.
+pub fn hash(password: &str) -> String {
+    let mut salt = [0; 16];
+    getrandom::getrandom(&mut salt).expect("the operating system has no random numbers");
+    let key = derive(password, &salt, ITERATIONS);
+    format!("pbkdf2-sha256${}${}${}", ITERATIONS, hex(&salt), hex(&key))
+}
+
+/// Whether `password` is the one `secret` was made from. Anything that isn't a hash `hash`
+/// made matches nothing.
+pub fn verify(password: &str, secret: &str) -> bool {
+    let mut parts = secret.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(key), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) else { return false };
+    let (Ok(iterations), Some(salt), Some(key)) = (iterations.parse(), unhex(salt), unhex(key)) else { return false };
+    derive(password, &salt, iterations).ct_eq(&key[..]).into()
+}
+
+fn derive(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations.max(1), &mut key);
+    key
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|c| c.is_ascii_hexdigit()) { return None; }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_the_standard_key() {
+        // From RFC 7914, section 11
+        assert_eq!(hex(&derive("passwd", b"salt", 1)), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+    }
+
+    #[test]
+    fn only_the_right_password_verifies() {
+        let secret = hash("hunter2");
+        assert!(verify("hunter2", &secret));
+        assert!(!verify("hunter3", &secret));
+        assert!(!verify("hunter2", "hunter2"));
+        assert!(!verify("hunter2", &secret[..secret.len() - 2]));
+        assert_ne!(hash("hunter2"), secret);
+    }
+}
@@ -0,0 +1,42 @@
+/// What a logged in user wants others to know about their availability.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Presence {
+    Online,
+    Away,
+    Busy,
+    /// Logged in, but shown to everyone else as if they weren't.
+    Invisible,
+    /// Not logged in. Only the server uses this, setting it means going invisible.
+    Offline,
+}
+
+impl Presence {
+    pub fn code(self) -> u8 {
+        match self {
+            Presence::Online    => 0,
+            Presence::Away      => 1,
+            Presence::Busy      => 2,
+            Presence::Invisible => 3,
+            Presence::Offline   => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Presence> {
+        match code {
+            0 => Some(Presence::Online),
+            1 => Some(Presence::Away),
+            2 => Some(Presence::Busy),
+            3 => Some(Presence::Invisible),
+            4 => Some(Presence::Offline),
+            _ => None,
+        }
+    }
+
+    /// What everyone but the user themselves gets to see.
+    pub fn shown(self) -> Presence {
+        match self {
+            Presence::Invisible => Presence::Offline,
+            presence => presence,
+        }
+    }
+}
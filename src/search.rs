@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+/// How `Command::Search` matches its pattern against nicknames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchMode {
+    /// The whole nickname, "all" lists everyone.
+    Exact,
+    Prefix,
+    /// `*` matches any number of characters and `?` exactly one.
+    Glob,
+    Substring,
+    /// Nicknames within a typo for every three characters of the pattern, or starting with
+    /// something that is.
+    Fuzzy,
+}
+
+impl SearchMode {
+    pub fn code(self) -> u8 {
+        match self {
+            SearchMode::Exact     => 0,
+            SearchMode::Prefix    => 1,
+            SearchMode::Glob      => 2,
+            SearchMode::Substring => 3,
+            SearchMode::Fuzzy     => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<SearchMode> {
+        match code {
+            0 => Some(SearchMode::Exact),
+            1 => Some(SearchMode::Prefix),
+            2 => Some(SearchMode::Glob),
+            3 => Some(SearchMode::Substring),
+            4 => Some(SearchMode::Fuzzy),
+            _ => None,
+        }
+    }
+}
+
+/// Which part of the results to return, `limit` results starting at `offset`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Page {
+    pub offset: u32,
+    pub limit: u32,
+}
+
+/// How well `name` matches `pattern`, lower is better and `None` is no match at all. Both
+/// should be given as `nick::key`s so case doesn't matter. An empty pattern matches nothing
+/// whatever the mode.
+pub fn rank(mode: SearchMode, pattern: &str, name: &str) -> Option<u32> {
+    if pattern.is_empty() { return None; }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Characters of the name the pattern didn't account for
+    let extra = name.len().saturating_sub(pattern.len()) as u32;
+    match mode {
+        SearchMode::Exact => if pattern == name { Some(0) } else { None },
+        SearchMode::Prefix => if name.starts_with(&pattern) { Some(extra) } else { None },
+        SearchMode::Substring => {
+            let at = name.windows(pattern.len()).position(|w| w == &pattern[..])?;
+            // Matches at the start of the name rank above those further in
+            Some(extra + (at > 0) as u32)
+        },
+        SearchMode::Glob => {
+            if !glob(&pattern, &name) { return None; }
+            let literal = pattern.iter().filter(|c| **c != '*' && **c != '?').count();
+            Some(name.len().saturating_sub(literal) as u32)
+        },
+        SearchMode::Fuzzy => {
+            // A typo for every three characters, so patterns shorter than that have to be
+            // spelled right, in full or as the start of the name
+            let typos = (pattern.len() / 3) as u32;
+            let whole = distance(&pattern, &name);
+            let start = distance(&pattern, &name[..pattern.len().min(name.len())]);
+            if whole.min(start) > typos { return None; }
+            // Matching the whole name ranks above matching the start of it just as well
+            Some(whole.min(start + 1))
+        },
+    }
+}
+
+fn glob(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where to pick up again if what followed the last `*` stops matching
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match star {
+                Some((after, from)) => {
+                    star = Some((after, from + 1));
+                    p = after;
+                    n = from + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Edit distance where swapping two neighbouring characters counts as a single typo
+fn distance(a: &[char], b: &[char]) -> u32 {
+    let mut d = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() { row[0] = i as u32; }
+    for (j, cell) in d[0].iter_mut().enumerate() { *cell = j as u32; }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as u32;
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// The words of a message as `Command::SearchMessages` sees them, lowercased runs of letters
+/// and digits.
+pub fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Inverted index over message text, which positions in the history each word shows up at.
+#[derive(Default)]
+pub struct Index {
+    words: HashMap<String, Vec<usize>>,
+}
+
+impl Index {
+    /// Positions have to be added in increasing order.
+    pub fn add(&mut self, pos: usize, text: &str) {
+        let mut words: Vec<String> = words(text).collect();
+        words.sort();
+        words.dedup();
+        for word in words {
+            self.words.entry(word).or_default().push(pos);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.words.clear();
+    }
+
+    /// Positions of the messages with every word of the query in them, in increasing order.
+    pub fn find(&self, query: &str) -> Vec<usize> {
+        let mut lists = Vec::new();
+        for word in words(query) {
+            match self.words.get(&word) {
+                Some(list) => lists.push(list),
+                None => return Vec::new(),
+            }
+        }
+        // Going from the rarest word keeps the lookups down
+        lists.sort_by_key(|list| list.len());
+        let Some((first, rest)) = lists.split_first() else { return Vec::new() };
+        first.iter()
+            .copied()
+            .filter(|pos| rest.iter().all(|list| list.binary_search(pos).is_ok()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [SearchMode; 5] = [SearchMode::Exact, SearchMode::Prefix, SearchMode::Glob, SearchMode::Substring, SearchMode::Fuzzy];
+
+    #[test]
+    fn empty_patterns_match_nothing() {
+        for mode in MODES {
+            assert_eq!(rank(mode, "", "bob"), None, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn closer_matches_rank_higher() {
+        assert_eq!(rank(SearchMode::Exact, "bob", "bob"), Some(0));
+        assert_eq!(rank(SearchMode::Exact, "bob", "bobby"), None);
+        assert_eq!(rank(SearchMode::Prefix, "bob", "bobby"), Some(2));
+        assert_eq!(rank(SearchMode::Substring, "ob", "bob"), Some(2));
+        assert_eq!(rank(SearchMode::Substring, "bo", "bob"), Some(1));
+        assert_eq!(rank(SearchMode::Glob, "b*b", "bob"), Some(1));
+        assert_eq!(rank(SearchMode::Glob, "b?", "bob"), None);
+    }
+
+    #[test]
+    fn short_fuzzy_patterns_allow_no_typos() {
+        assert_eq!(rank(SearchMode::Fuzzy, "x", "y"), None);
+        assert_eq!(rank(SearchMode::Fuzzy, "bo", "bob"), Some(1));
+        assert_eq!(rank(SearchMode::Fuzzy, "alcie", "alice"), Some(1));
+        assert_eq!(rank(SearchMode::Fuzzy, "alcie", "alice_smith"), Some(2));
+        assert_eq!(rank(SearchMode::Fuzzy, "alcie", "bob"), None);
+    }
+
+    #[test]
+    fn index_finds_messages_with_every_word() {
+        let mut index = Index::default();
+        index.add(0, "Lunch at noon?");
+        index.add(1, "no lunch today");
+        index.add(2, "noon works");
+        assert_eq!(index.find("LUNCH"), [0, 1]);
+        assert_eq!(index.find("lunch noon"), [0]);
+        assert!(index.find("dinner").is_empty());
+        assert!(index.find("").is_empty());
+    }
+}
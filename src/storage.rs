@@ -0,0 +1,425 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::ban::{Ban, BanTarget, Bans};
+use crate::config::Config;
+use crate::history::Record;
+use crate::{Serialize, Deserialize, Packet, string_packet, u64_packet, Msg, MsgId, Nickname, Timestamp};
+
+mod file;
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use file::FileStorage;
+pub use memory::MemoryStorage;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+/// Someone who has logged in at least once. Having an account is what lets others leave
+/// messages for a user while they are offline.
+#[derive(Debug, Clone)]
+pub struct Account {
+    /// As the user last wrote it, accounts are looked up by `nick::key`.
+    pub name: Nickname,
+    pub created: Timestamp,
+    pub last_seen: Timestamp,
+    /// The password as `password::hash` left it. Accounts from before passwords have none
+    /// until the next login sets one.
+    pub secret: Option<String>,
+}
+
+impl Serialize for Account {
+    fn serialize(&self) -> Vec<Packet> {
+        let mut packets = vec![
+            string_packet(&self.name, 0),
+            u64_packet(self.created, 0),
+            u64_packet(self.last_seen, 0),
+        ];
+        if let Some(secret) = &self.secret {
+            packets.push(string_packet(secret, 0));
+        }
+        packets
+    }
+}
+
+impl Deserialize<Account> for [Packet] {
+    fn deserialize(&self) -> Option<Account> {
+        Some(Account {
+            name: self.first()?.deserialize()?,
+            created: self.get(1)?.deserialize()?,
+            last_seen: self.get(2)?.deserialize()?,
+            secret: match self.get(3) {
+                Some(packet) => Some(packet.deserialize()?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// The nickname lists every user keeps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum List {
+    Contacts,
+    Blocks,
+}
+
+/// Everything the server keeps about its users and their messages. The server only ever goes
+/// through this, so where things are kept is up to the backend picked in the config. Users
+/// are given by their `nick::key`. There is nothing for channels, the server has none and
+/// every message is between two users.
+pub trait Storage: Send {
+    fn account(&self, key: &str) -> io::Result<Option<Account>>;
+    /// Creates or replaces the account of `nick::key(&account.name)`.
+    fn put_account(&mut self, account: &Account) -> io::Result<()>;
+    /// Moves the account of `old_key`, with its lists, queued messages, history and
+    /// reactions, over to `new`, and renames it in everyone else's lists.
+    fn rename_account(&mut self, old_key: &str, new: &str) -> io::Result<()>;
+
+    /// The names in `owner`'s list, in the order they were added.
+    fn list(&self, list: List, owner: &str) -> io::Result<Vec<Nickname>>;
+    fn set_list(&mut self, list: List, owner: &str, names: &[Nickname]) -> io::Result<()>;
+    /// The keys of everyone who has `key` in their list.
+    fn listed_by(&self, list: List, key: &str) -> io::Result<Vec<String>>;
+
+    fn bans(&self) -> io::Result<Bans>;
+    /// Adds the ban, replacing any earlier ban of the same target.
+    fn add_ban(&mut self, ban: &Ban) -> io::Result<()>;
+    /// Lifts the ban on `target`, returns whether there was one.
+    fn remove_ban(&mut self, target: &BanTarget) -> io::Result<bool>;
+
+    /// Keeps a message for a user who is offline until they ask for it.
+    fn queue(&mut self, to: &str, record: &Record) -> io::Result<()>;
+    /// The messages queued for the user, oldest first, and forgets them.
+    fn take_queued(&mut self, key: &str) -> io::Result<Vec<Record>>;
+    /// Whether the message is waiting in the user's queue.
+    fn is_queued(&self, key: &str, id: MsgId) -> io::Result<bool>;
+
+    /// Looks up a routed message by its id.
+    fn message(&self, id: MsgId) -> io::Result<Option<Record>>;
+    /// Adds the message to the history. A message with an id that is already stored is not
+    /// stored again, so clients can retry a message without it showing up twice.
+    fn add_message(&mut self, record: &Record) -> io::Result<()>;
+    /// Replaces the text of the message, in the history and in the queue it waits in. Returns
+    /// whether there was such a message.
+    fn edit_message(&mut self, id: MsgId, msg: &str) -> io::Result<bool>;
+    /// Deletes the message, with its reactions, from the history and from the queue it waits
+    /// in. Returns whether there was such a message.
+    fn delete_message(&mut self, id: MsgId) -> io::Result<bool>;
+    /// Every reaction to the message, in the order they were first used, each with who
+    /// reacted with it.
+    fn reactions(&self, id: MsgId) -> io::Result<Vec<(Msg, Vec<Nickname>)>>;
+    /// Adds `name`'s reaction to the message, returns false if it was already there.
+    fn react(&mut self, id: MsgId, emoji: &str, name: &str) -> io::Result<bool>;
+    /// Takes back `key`'s reaction to the message, returns false if there wasn't one.
+    fn unreact(&mut self, id: MsgId, emoji: &str, key: &str) -> io::Result<bool>;
+    /// Every message `key` sent or received, oldest first.
+    fn user_messages(&self, key: &str) -> io::Result<Vec<Record>>;
+    /// The newest `limit` messages between `a` and `b` that come before the `Record::position`
+    /// `before`, oldest first.
+    fn conversation(&self, a: &str, b: &str, before: Option<(Timestamp, MsgId)>, limit: usize) -> io::Result<Vec<Record>>;
+    /// The first `limit` messages between `a` and `b` that come after the `Record::position`
+    /// `after`, oldest first.
+    fn following(&self, a: &str, b: &str, after: (Timestamp, MsgId), limit: usize) -> io::Result<Vec<Record>>;
+    /// The messages `key` sent or received with every word of `query` in them, as
+    /// `search::words` splits it, newest first. Only those exchanged with `peer` and sent
+    /// since `since` if given.
+    fn search_messages(&self, key: &str, query: &str, peer: Option<&str>, since: Option<Timestamp>) -> io::Result<Vec<Record>>;
+    /// Deletes the messages that expired by `now` and the ones sent before `sent_before`, both
+    /// from the history and from the queues, along with their reactions. Returns how many left
+    /// the history.
+    fn expire(&mut self, now: Timestamp, sent_before: Option<Timestamp>) -> io::Result<usize>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// Nothing is kept once the server stops.
+    Memory,
+    /// A directory of files, one for each kind of data, and the history in `history_file`.
+    File,
+    /// A single SQLite database, only there when built with the `sqlite` feature.
+    Sqlite,
+}
+
+impl FromStr for Backend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Backend, ()> {
+        match s {
+            "memory" => Ok(Backend::Memory),
+            "file" => Ok(Backend::File),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Opens the backend the config asks for in `data_dir`, bringing it up to date first.
+pub fn open(config: &Config) -> io::Result<Box<dyn Storage>> {
+    let mut storage: Box<dyn Storage> = match config.storage {
+        Backend::Memory => Box::new(MemoryStorage::default()),
+        Backend::File => Box::new(FileStorage::open(&config.data_dir, &config.history_file)?),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => {
+            fs::create_dir_all(&config.data_dir)?;
+            let mut storage = SqliteStorage::open(Path::new(&config.data_dir).join("chat.db"))?;
+            import_history(&mut storage, &config.history_file)?;
+            Box::new(storage)
+        },
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => return Err(io::Error::other("the server was built without the sqlite feature")),
+    };
+    import_bans(storage.as_mut(), &config.bans_file)?;
+    Ok(storage)
+}
+
+// Bans used to be kept in a file of their own. They are moved over the first time the server
+// starts with a storage backend, and the old file is renamed so it isn't imported again.
+fn import_bans(storage: &mut dyn Storage, path: &str) -> io::Result<()> {
+    if path.is_empty() || !Path::new(path).exists() { return Ok(()); }
+    for frame in read_frames(Path::new(path))? {
+        let ban: Ban = frame[..].deserialize().ok_or_else(|| corrupt("ban"))?;
+        storage.add_ban(&ban)?;
+    }
+    fs::rename(path, format!("{}.imported", path))
+}
+
+// Messages are kept in the database with the rest, an existing history file is moved over
+// the same way as the old ban list
+#[cfg(feature = "sqlite")]
+fn import_history(storage: &mut dyn Storage, path: &str) -> io::Result<()> {
+    if path.is_empty() || !Path::new(path).exists() { return Ok(()); }
+    for frame in read_frames(Path::new(path))? {
+        let record: Record = frame[..].deserialize().ok_or_else(|| corrupt("history record"))?;
+        storage.add_message(&record)?;
+    }
+    fs::rename(path, format!("{}.imported", path))
+}
+
+// Every frame in the file, none if there is no file yet
+fn read_frames(path: &Path) -> io::Result<Vec<Vec<Packet>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file);
+    let mut frames = Vec::new();
+    while let Some(frame) = Packet::read_frame(&mut reader)? {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // A directory of its own for every test, gone again when the test is done
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!("chat_server-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed));
+            let dir = std::env::temp_dir().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Runs the test against every backend, the file one in a fresh directory
+    fn each_backend(test: fn(&mut dyn Storage)) {
+        test(&mut MemoryStorage::default());
+        let dir = TempDir::new();
+        test(&mut FileStorage::open(dir.0.join("data"), dir.0.join("history.db")).unwrap());
+        #[cfg(feature = "sqlite")]
+        test(&mut SqliteStorage::open(":memory:").unwrap());
+    }
+
+    fn record(id: MsgId, from: &str, to: &str, timestamp: Timestamp, msg: &str) -> Record {
+        Record { id, from: from.to_string(), to: to.to_string(), timestamp, msg: msg.to_string(), expires: None }
+    }
+
+    fn ids(records: &[Record]) -> Vec<MsgId> {
+        records.iter().map(|record| record.id).collect()
+    }
+
+    fn account(name: &str) -> Account {
+        Account { name: name.to_string(), created: 1, last_seen: 2, secret: Some("secret".to_string()) }
+    }
+
+    #[test]
+    fn add_message_ignores_a_retry() {
+        each_backend(|storage| {
+            storage.add_message(&record(1, "alice", "bob", 10, "first")).unwrap();
+            storage.add_message(&record(1, "alice", "bob", 11, "second")).unwrap();
+            assert_eq!(storage.message(1).unwrap().unwrap().msg, "first");
+            assert_eq!(ids(&storage.user_messages("alice").unwrap()), [1]);
+        });
+    }
+
+    #[test]
+    fn is_queued_only_finds_messages_still_waiting() {
+        each_backend(|storage| {
+            storage.queue("bob", &record(1, "alice", "bob", 10, "hi")).unwrap();
+            assert!(storage.is_queued("bob", 1).unwrap());
+            assert!(!storage.is_queued("bob", 2).unwrap());
+            assert!(!storage.is_queued("alice", 1).unwrap());
+            storage.take_queued("bob").unwrap();
+            assert!(!storage.is_queued("bob", 1).unwrap());
+        });
+    }
+
+    #[test]
+    fn edit_message_changes_history_and_queue() {
+        each_backend(|storage| {
+            let hello = record(1, "alice", "bob", 10, "helo");
+            storage.add_message(&hello).unwrap();
+            storage.queue("bob", &hello).unwrap();
+            assert!(storage.edit_message(1, "hello").unwrap());
+            assert!(!storage.edit_message(2, "nothing").unwrap());
+            assert_eq!(storage.message(1).unwrap().unwrap().msg, "hello");
+            assert_eq!(storage.search_messages("bob", "hello", None, None).unwrap().len(), 1);
+            assert!(storage.search_messages("bob", "helo", None, None).unwrap().is_empty());
+            assert_eq!(storage.take_queued("bob").unwrap()[0].msg, "hello");
+        });
+    }
+
+    #[test]
+    fn delete_message_takes_its_reactions_along() {
+        each_backend(|storage| {
+            let hello = record(1, "alice", "bob", 10, "hello");
+            storage.add_message(&hello).unwrap();
+            storage.queue("bob", &hello).unwrap();
+            assert!(storage.react(1, "👍", "bob").unwrap());
+            assert!(storage.delete_message(1).unwrap());
+            assert!(!storage.delete_message(1).unwrap());
+            assert!(storage.message(1).unwrap().is_none());
+            assert!(storage.reactions(1).unwrap().is_empty());
+            assert!(storage.take_queued("bob").unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn expire_deletes_expired_and_old_messages() {
+        each_backend(|storage| {
+            let old = record(1, "alice", "bob", 10, "old");
+            let expiring = Record { expires: Some(50), ..record(2, "alice", "bob", 30, "expiring") };
+            let kept = Record { expires: Some(200), ..record(3, "alice", "bob", 40, "kept") };
+            for record in [&old, &expiring, &kept] {
+                storage.add_message(record).unwrap();
+                storage.queue("bob", record).unwrap();
+            }
+            storage.react(2, "👍", "bob").unwrap();
+            assert_eq!(storage.expire(100, Some(20)).unwrap(), 2);
+            assert_eq!(ids(&storage.user_messages("alice").unwrap()), [3]);
+            assert_eq!(ids(&storage.take_queued("bob").unwrap()), [3]);
+            assert!(storage.reactions(2).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn rename_account_takes_everything_along() {
+        each_backend(|storage| {
+            storage.put_account(&account("alice")).unwrap();
+            storage.set_list(List::Contacts, "alice", &["bob".to_string()]).unwrap();
+            storage.set_list(List::Contacts, "bob", &["Alice".to_string()]).unwrap();
+            storage.add_message(&record(1, "Alice", "bob", 10, "hi")).unwrap();
+            storage.add_message(&record(2, "bob", "alice", 11, "hello")).unwrap();
+            storage.queue("alice", &record(2, "bob", "alice", 11, "hello")).unwrap();
+            storage.queue("bob", &record(1, "Alice", "bob", 10, "hi")).unwrap();
+            storage.react(2, "👍", "alice").unwrap();
+
+            storage.rename_account("alice", "Alicia").unwrap();
+            assert!(storage.account("alice").unwrap().is_none());
+            let renamed = storage.account("alicia").unwrap().unwrap();
+            assert_eq!((renamed.name.as_str(), renamed.secret), ("Alicia", Some("secret".to_string())));
+            assert_eq!(storage.list(List::Contacts, "alicia").unwrap(), ["bob"]);
+            assert_eq!(storage.list(List::Contacts, "bob").unwrap(), ["Alicia"]);
+            assert!(storage.user_messages("alice").unwrap().is_empty());
+            assert_eq!(ids(&storage.conversation("alicia", "bob", None, 10).unwrap()), [1, 2]);
+            assert_eq!(storage.message(1).unwrap().unwrap().from, "Alicia");
+            assert_eq!(storage.reactions(2).unwrap(), [("👍".to_string(), vec!["Alicia".to_string()])]);
+            assert!(storage.unreact(2, "👍", "alicia").unwrap());
+            assert_eq!(storage.take_queued("alicia").unwrap()[0].to, "Alicia");
+            assert_eq!(storage.take_queued("bob").unwrap()[0].from, "Alicia");
+        });
+    }
+
+    #[test]
+    fn conversation_pages_through_messages_sent_at_once() {
+        each_backend(|storage| {
+            for id in 1..=5 {
+                storage.add_message(&record(id, "alice", "bob", 10, "same time")).unwrap();
+            }
+            storage.add_message(&record(6, "alice", "carol", 10, "someone else")).unwrap();
+            let page = storage.conversation("alice", "bob", None, 2).unwrap();
+            assert_eq!(ids(&page), [4, 5]);
+            let page = storage.conversation("bob", "alice", Some(page[0].position()), 2).unwrap();
+            assert_eq!(ids(&page), [2, 3]);
+            let page = storage.conversation("alice", "bob", Some(page[0].position()), 2).unwrap();
+            assert_eq!(ids(&page), [1]);
+            assert_eq!(ids(&storage.following("alice", "bob", (10, 2), 2).unwrap()), [3, 4]);
+        });
+    }
+
+    #[test]
+    fn file_storage_keeps_changes_across_restarts() {
+        let dir = TempDir::new();
+        let open = || FileStorage::open(dir.0.join("data"), dir.0.join("history.db")).unwrap();
+        let mut storage = open();
+        storage.put_account(&account("alice")).unwrap();
+        storage.add_message(&record(1, "alice", "bob", 10, "helo")).unwrap();
+        storage.add_message(&record(2, "alice", "bob", 11, "gone")).unwrap();
+        storage.edit_message(1, "hello").unwrap();
+        storage.delete_message(2).unwrap();
+        storage.react(1, "👍", "bob").unwrap();
+        storage.rename_account("alice", "alicia").unwrap();
+        drop(storage);
+
+        let storage = open();
+        assert!(storage.account("alicia").unwrap().is_some());
+        let history = storage.user_messages("bob").unwrap();
+        assert_eq!(ids(&history), [1]);
+        assert_eq!((history[0].from.as_str(), history[0].msg.as_str()), ("alicia", "hello"));
+        assert_eq!(storage.reactions(1).unwrap(), [("👍".to_string(), vec!["bob".to_string()])]);
+    }
+
+    #[test]
+    fn file_storage_migrates_queued_messages_without_expiry() {
+        let dir = TempDir::new();
+        let data = dir.0.join("data");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("version"), "1\n").unwrap();
+        // Records were five packets long before they could expire
+        let mut frame = vec![string_packet("bob", 0)];
+        for record in [record(1, "alice", "bob", 10, "hi"), record(2, "carol", "bob", 11, "hey")] {
+            frame.extend(record.serialize().into_iter().take(5));
+        }
+        let mut bytes = Packet::to_byte_vec(frame);
+        bytes.extend_from_slice(&[0, 0, 0, 0]);
+        fs::write(data.join("queued"), bytes).unwrap();
+
+        let mut storage = FileStorage::open(&data, dir.0.join("history.db")).unwrap();
+        let queued = storage.take_queued("bob").unwrap();
+        assert_eq!(ids(&queued), [1, 2]);
+        assert_eq!((queued[1].msg.as_str(), queued[1].expires), ("hey", None));
+        assert_eq!(fs::read_to_string(data.join("version")).unwrap(), "2\n");
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::ban::{Ban, BanTarget, Bans};
+use crate::history::{History, Record, RECORD_PACKETS};
+use crate::nick;
+use crate::{Serialize, Deserialize, Packet, string_packet, u64_packet, Msg, MsgId, Nickname, Timestamp};
+use super::{Account, List, MemoryStorage, Storage, read_frames, corrupt};
+
+// Each step brings the directory from the version before it up to the next one
+const MIGRATIONS: &[fn(&Path) -> io::Result<()>] = &[
+    initial_layout,
+    expiring_records,
+];
+
+/// Keeps everything in memory, and each kind of data in a file of its own in a directory. A
+/// file is written over in full whenever something in it changes. New messages are appended
+/// to the history, but editing, deleting, renaming and expiring messages write all of it
+/// over too, which takes longer the longer the history gets. `SqliteStorage` changes only
+/// the rows involved.
+pub struct FileStorage {
+    dir: PathBuf,
+    data: MemoryStorage,
+}
+
+impl FileStorage {
+    pub fn open<P: Into<PathBuf>, H: Into<PathBuf>>(dir: P, history_file: H) -> io::Result<FileStorage> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        migrate(&dir)?;
+
+        let mut data = MemoryStorage::default();
+        for frame in read_frames(&dir.join("accounts"))? {
+            let account: Account = frame[..].deserialize().ok_or_else(|| corrupt("account"))?;
+            data.accounts.insert(nick::key(&account.name), account);
+        }
+        let mut bans = Vec::new();
+        for frame in read_frames(&dir.join("bans"))? {
+            bans.push(frame[..].deserialize().ok_or_else(|| corrupt("ban"))?);
+        }
+        data.bans = Bans::new(bans);
+        for frame in read_frames(&dir.join("queued"))? {
+            let (key, rest) = keyed(&frame).ok_or_else(|| corrupt("queued message"))?;
+            let records = rest.chunks(RECORD_PACKETS)
+                .map(|packets| packets.deserialize())
+                .collect::<Option<Vec<Record>>>()
+                .ok_or_else(|| corrupt("queued message"))?;
+            data.queued.insert(key, records);
+        }
+        data.contacts = read_lists(&dir.join("contacts"))?;
+        data.blocks = read_lists(&dir.join("blocks"))?;
+        data.history = History::open(history_file)?;
+        for frame in read_frames(&dir.join("reactions"))? {
+            let (id, emoji, names) = reaction(&frame).ok_or_else(|| corrupt("reaction"))?;
+            data.reactions.entry(id).or_default().push((emoji, names));
+        }
+        Ok(FileStorage { dir, data })
+    }
+
+    fn save_accounts(&self) -> io::Result<()> {
+        write_frames(&self.dir.join("accounts"), self.data.accounts.values().map(Serialize::serialize))
+    }
+
+    fn save_list(&self, list: List) -> io::Result<()> {
+        let file = match list {
+            List::Contacts => "contacts",
+            List::Blocks => "blocks",
+        };
+        let frames = self.data.lists(list).iter()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(owner, names)| {
+                let mut frame = vec![string_packet(owner, 0)];
+                frame.extend(names.iter().map(|name| string_packet(name, 0)));
+                frame
+            });
+        write_frames(&self.dir.join(file), frames)
+    }
+
+    fn save_bans(&mut self) -> io::Result<()> {
+        self.data.bans.prune(crate::now());
+        write_frames(&self.dir.join("bans"), self.data.bans.all().iter().map(Serialize::serialize))
+    }
+
+    // A frame for each reaction to a message, with the id and the emoji first
+    fn save_reactions(&self) -> io::Result<()> {
+        let frames = self.data.reactions.iter()
+            .flat_map(|(id, reactions)| reactions.iter().map(move |(emoji, names)| {
+                let mut frame = vec![u64_packet(*id, 0), string_packet(emoji, 0)];
+                frame.extend(names.iter().map(|name| string_packet(name, 0)));
+                frame
+            }));
+        write_frames(&self.dir.join("reactions"), frames)
+    }
+
+    fn save_queued(&self) -> io::Result<()> {
+        let frames = self.data.queued.iter()
+            .filter(|(_, records)| !records.is_empty())
+            .map(|(key, records)| {
+                let mut frame = vec![string_packet(key, 0)];
+                frame.extend(records.iter().flat_map(Serialize::serialize));
+                frame
+            });
+        write_frames(&self.dir.join("queued"), frames)
+    }
+}
+
+impl Storage for FileStorage {
+    fn account(&self, key: &str) -> io::Result<Option<Account>> {
+        self.data.account(key)
+    }
+
+    fn put_account(&mut self, account: &Account) -> io::Result<()> {
+        self.data.put_account(account)?;
+        self.save_accounts()
+    }
+
+    fn rename_account(&mut self, old_key: &str, new: &str) -> io::Result<()> {
+        self.data.rename_account(old_key, new)?;
+        self.save_accounts()?;
+        self.save_list(List::Contacts)?;
+        self.save_list(List::Blocks)?;
+        self.save_queued()?;
+        self.save_reactions()
+    }
+
+    fn list(&self, list: List, owner: &str) -> io::Result<Vec<Nickname>> {
+        self.data.list(list, owner)
+    }
+
+    fn set_list(&mut self, list: List, owner: &str, names: &[Nickname]) -> io::Result<()> {
+        self.data.set_list(list, owner, names)?;
+        self.save_list(list)
+    }
+
+    fn listed_by(&self, list: List, key: &str) -> io::Result<Vec<String>> {
+        self.data.listed_by(list, key)
+    }
+
+    fn bans(&self) -> io::Result<Bans> {
+        self.data.bans()
+    }
+
+    fn add_ban(&mut self, ban: &Ban) -> io::Result<()> {
+        self.data.add_ban(ban)?;
+        self.save_bans()
+    }
+
+    fn remove_ban(&mut self, target: &BanTarget) -> io::Result<bool> {
+        let removed = self.data.remove_ban(target)?;
+        self.save_bans()?;
+        Ok(removed)
+    }
+
+    fn queue(&mut self, to: &str, record: &Record) -> io::Result<()> {
+        self.data.queue(to, record)?;
+        self.save_queued()
+    }
+
+    fn take_queued(&mut self, key: &str) -> io::Result<Vec<Record>> {
+        let records = self.data.take_queued(key)?;
+        if !records.is_empty() { self.save_queued()?; }
+        Ok(records)
+    }
+
+    fn is_queued(&self, key: &str, id: MsgId) -> io::Result<bool> {
+        self.data.is_queued(key, id)
+    }
+
+    fn message(&self, id: MsgId) -> io::Result<Option<Record>> {
+        self.data.message(id)
+    }
+
+    fn add_message(&mut self, record: &Record) -> io::Result<()> {
+        self.data.add_message(record)
+    }
+
+    fn edit_message(&mut self, id: MsgId, msg: &str) -> io::Result<bool> {
+        let found = self.data.edit_message(id, msg)?;
+        if found { self.save_queued()?; }
+        Ok(found)
+    }
+
+    fn delete_message(&mut self, id: MsgId) -> io::Result<bool> {
+        let found = self.data.delete_message(id)?;
+        if found {
+            self.save_queued()?;
+            self.save_reactions()?;
+        }
+        Ok(found)
+    }
+
+    fn reactions(&self, id: MsgId) -> io::Result<Vec<(Msg, Vec<Nickname>)>> {
+        self.data.reactions(id)
+    }
+
+    fn react(&mut self, id: MsgId, emoji: &str, name: &str) -> io::Result<bool> {
+        let added = self.data.react(id, emoji, name)?;
+        if added { self.save_reactions()?; }
+        Ok(added)
+    }
+
+    fn unreact(&mut self, id: MsgId, emoji: &str, key: &str) -> io::Result<bool> {
+        let removed = self.data.unreact(id, emoji, key)?;
+        if removed { self.save_reactions()?; }
+        Ok(removed)
+    }
+
+    fn user_messages(&self, key: &str) -> io::Result<Vec<Record>> {
+        self.data.user_messages(key)
+    }
+
+    fn conversation(&self, a: &str, b: &str, before: Option<(Timestamp, MsgId)>, limit: usize) -> io::Result<Vec<Record>> {
+        self.data.conversation(a, b, before, limit)
+    }
+
+    fn following(&self, a: &str, b: &str, after: (Timestamp, MsgId), limit: usize) -> io::Result<Vec<Record>> {
+        self.data.following(a, b, after, limit)
+    }
+
+    fn search_messages(&self, key: &str, query: &str, peer: Option<&str>, since: Option<Timestamp>) -> io::Result<Vec<Record>> {
+        self.data.search_messages(key, query, peer, since)
+    }
+
+    fn expire(&mut self, now: Timestamp, sent_before: Option<Timestamp>) -> io::Result<usize> {
+        let removed = self.data.expire(now, sent_before)?;
+        // Queued messages are in the history too, so nothing leaving it means nothing changed
+        if removed > 0 {
+            self.save_queued()?;
+            self.save_reactions()?;
+        }
+        Ok(removed)
+    }
+}
+
+// The directory keeps the number of the last migration run on it in `version`
+fn migrate(dir: &Path) -> io::Result<()> {
+    let path = dir.join("version");
+    let version: usize = match fs::read_to_string(&path) {
+        Ok(text) => text.trim().parse().map_err(|_| corrupt("version"))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    if version > MIGRATIONS.len() {
+        return Err(io::Error::other(format!("{} is from a newer version of the server", dir.display())));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        migration(dir)?;
+        write_atomic(&path, format!("{}\n", i + 1).as_bytes())?;
+    }
+    Ok(())
+}
+
+// Version 1, the files are created as they are first written so there is nothing to do
+fn initial_layout(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Version 2, records got a packet for when they expire. Queued messages are packed into one
+// frame per user, so the old ones have to be told apart by their length.
+fn expiring_records(dir: &Path) -> io::Result<()> {
+    const OLD_RECORD_PACKETS: usize = 5;
+    let path = dir.join("queued");
+    let mut frames = Vec::new();
+    for frame in read_frames(&path)? {
+        let (key, rest) = keyed(&frame).ok_or_else(|| corrupt("queued message"))?;
+        let mut new = vec![string_packet(&key, 0)];
+        for packets in rest.chunks(OLD_RECORD_PACKETS) {
+            let record: Record = packets.deserialize().ok_or_else(|| corrupt("queued message"))?;
+            new.append(&mut record.serialize());
+        }
+        frames.push(new);
+    }
+    write_frames(&path, frames.into_iter())
+}
+
+fn read_lists(path: &Path) -> io::Result<HashMap<String, Vec<Nickname>>> {
+    let mut lists = HashMap::new();
+    for frame in read_frames(path)? {
+        let (owner, rest) = keyed(&frame).ok_or_else(|| corrupt("list"))?;
+        let names = rest.iter()
+            .map(|packet| packet.deserialize())
+            .collect::<Option<Vec<Nickname>>>()
+            .ok_or_else(|| corrupt("list"))?;
+        lists.insert(owner, names);
+    }
+    Ok(lists)
+}
+
+fn reaction(frame: &[Packet]) -> Option<(MsgId, Msg, Vec<Nickname>)> {
+    let (id, rest) = frame.split_first()?;
+    let (emoji, names) = rest.split_first()?;
+    let names = names.iter().map(|packet| packet.deserialize()).collect::<Option<Vec<Nickname>>>()?;
+    Some((id.deserialize()?, emoji.deserialize()?, names))
+}
+
+// A frame that starts with the key of the user it belongs to
+fn keyed(frame: &[Packet]) -> Option<(String, &[Packet])> {
+    let (key, rest) = frame.split_first()?;
+    Some((key.deserialize()?, rest))
+}
+
+fn write_frames<I: Iterator<Item = Vec<Packet>>>(path: &Path, frames: I) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    for frame in frames {
+        bytes.append(&mut Packet::to_byte_vec(frame));
+        bytes.extend_from_slice(&[0, 0, 0, 0]);
+    }
+    write_atomic(path, &bytes)
+}
+
+// Written to a new file first, so a crash halfway through doesn't lose the old one
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    File::create(&tmp)?.write_all(bytes)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::ban::{Ban, BanTarget, Bans};
+use crate::history::{History, Record};
+use crate::nick;
+use crate::{Msg, MsgId, Nickname, Timestamp};
+use super::{Account, List, Storage};
+
+/// Keeps everything in memory, so it is all gone once the server stops. `FileStorage` builds
+/// on it.
+#[derive(Default)]
+pub struct MemoryStorage {
+    pub(super) accounts: HashMap<String, Account>,
+    pub(super) contacts: HashMap<String, Vec<Nickname>>,
+    pub(super) blocks: HashMap<String, Vec<Nickname>>,
+    pub(super) bans: Bans,
+    pub(super) queued: HashMap<String, Vec<Record>>,
+    pub(super) history: History,
+    pub(super) reactions: HashMap<MsgId, Vec<(Msg, Vec<Nickname>)>>,
+}
+
+impl MemoryStorage {
+    pub(super) fn lists(&self, list: List) -> &HashMap<String, Vec<Nickname>> {
+        match list {
+            List::Contacts => &self.contacts,
+            List::Blocks => &self.blocks,
+        }
+    }
+
+    fn lists_mut(&mut self, list: List) -> &mut HashMap<String, Vec<Nickname>> {
+        match list {
+            List::Contacts => &mut self.contacts,
+            List::Blocks => &mut self.blocks,
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn account(&self, key: &str) -> io::Result<Option<Account>> {
+        Ok(self.accounts.get(key).cloned())
+    }
+
+    fn put_account(&mut self, account: &Account) -> io::Result<()> {
+        self.accounts.insert(nick::key(&account.name), account.clone());
+        Ok(())
+    }
+
+    fn rename_account(&mut self, old_key: &str, new: &str) -> io::Result<()> {
+        let new_key = nick::key(new);
+        if let Some(mut account) = self.accounts.remove(old_key) {
+            account.name = new.to_string();
+            self.accounts.insert(new_key.clone(), account);
+        }
+        for lists in [&mut self.contacts, &mut self.blocks] {
+            if let Some(names) = lists.remove(old_key) {
+                lists.insert(new_key.clone(), names);
+            }
+            for names in lists.values_mut() {
+                for name in names.iter_mut().filter(|n| nick::key(n) == old_key) {
+                    *name = new.to_string();
+                }
+            }
+        }
+        if let Some(records) = self.queued.remove(old_key) {
+            self.queued.entry(new_key).or_default().extend(records);
+        }
+        for record in self.queued.values_mut().flatten() {
+            record.rename(old_key, new);
+        }
+        for (_, names) in self.reactions.values_mut().flatten() {
+            for name in names.iter_mut().filter(|n| nick::key(n) == old_key) {
+                *name = new.to_string();
+            }
+        }
+        self.history.rename(old_key, new)
+    }
+
+    fn list(&self, list: List, owner: &str) -> io::Result<Vec<Nickname>> {
+        Ok(self.lists(list).get(owner).cloned().unwrap_or_default())
+    }
+
+    fn set_list(&mut self, list: List, owner: &str, names: &[Nickname]) -> io::Result<()> {
+        self.lists_mut(list).insert(owner.to_string(), names.to_vec());
+        Ok(())
+    }
+
+    fn listed_by(&self, list: List, key: &str) -> io::Result<Vec<String>> {
+        Ok(self.lists(list).iter()
+            .filter(|(_, names)| names.iter().any(|name| nick::key(name) == key))
+            .map(|(owner, _)| owner.clone())
+            .collect())
+    }
+
+    fn bans(&self) -> io::Result<Bans> {
+        Ok(self.bans.clone())
+    }
+
+    fn add_ban(&mut self, ban: &Ban) -> io::Result<()> {
+        self.bans.add(ban.clone());
+        Ok(())
+    }
+
+    fn remove_ban(&mut self, target: &BanTarget) -> io::Result<bool> {
+        Ok(self.bans.remove(target))
+    }
+
+    fn queue(&mut self, to: &str, record: &Record) -> io::Result<()> {
+        self.queued.entry(to.to_string()).or_default().push(record.clone());
+        Ok(())
+    }
+
+    fn take_queued(&mut self, key: &str) -> io::Result<Vec<Record>> {
+        Ok(self.queued.remove(key).unwrap_or_default())
+    }
+
+    fn is_queued(&self, key: &str, id: MsgId) -> io::Result<bool> {
+        Ok(self.queued.get(key).is_some_and(|records| records.iter().any(|record| record.id == id)))
+    }
+
+    fn message(&self, id: MsgId) -> io::Result<Option<Record>> {
+        Ok(self.history.get(id).cloned())
+    }
+
+    fn add_message(&mut self, record: &Record) -> io::Result<()> {
+        self.history.append(record.clone())
+    }
+
+    fn edit_message(&mut self, id: MsgId, msg: &str) -> io::Result<bool> {
+        for record in self.queued.values_mut().flatten().filter(|record| record.id == id) {
+            record.msg = msg.to_string();
+        }
+        self.history.edit(id, msg)
+    }
+
+    fn delete_message(&mut self, id: MsgId) -> io::Result<bool> {
+        for records in self.queued.values_mut() {
+            records.retain(|record| record.id != id);
+        }
+        self.queued.retain(|_, records| !records.is_empty());
+        self.reactions.remove(&id);
+        self.history.remove(id)
+    }
+
+    fn reactions(&self, id: MsgId) -> io::Result<Vec<(Msg, Vec<Nickname>)>> {
+        Ok(self.reactions.get(&id).cloned().unwrap_or_default())
+    }
+
+    fn react(&mut self, id: MsgId, emoji: &str, name: &str) -> io::Result<bool> {
+        let key = nick::key(name);
+        let reactions = self.reactions.entry(id).or_default();
+        let i = match reactions.iter().position(|(e, _)| e == emoji) {
+            Some(i) => i,
+            None => {
+                reactions.push((emoji.to_string(), Vec::new()));
+                reactions.len() - 1
+            },
+        };
+        let names = &mut reactions[i].1;
+        if names.iter().any(|n| nick::key(n) == key) { return Ok(false); }
+        names.push(name.to_string());
+        Ok(true)
+    }
+
+    fn unreact(&mut self, id: MsgId, emoji: &str, key: &str) -> io::Result<bool> {
+        let Some(reactions) = self.reactions.get_mut(&id) else { return Ok(false) };
+        let Some(i) = reactions.iter().position(|(e, _)| e == emoji) else { return Ok(false) };
+        let names = &mut reactions[i].1;
+        let before = names.len();
+        names.retain(|n| nick::key(n) != key);
+        let removed = names.len() < before;
+        if names.is_empty() { reactions.remove(i); }
+        if reactions.is_empty() { self.reactions.remove(&id); }
+        Ok(removed)
+    }
+
+    fn user_messages(&self, key: &str) -> io::Result<Vec<Record>> {
+        Ok(self.history.involving(key))
+    }
+
+    fn conversation(&self, a: &str, b: &str, before: Option<(Timestamp, MsgId)>, limit: usize) -> io::Result<Vec<Record>> {
+        Ok(self.history.conversation(a, b, before, limit))
+    }
+
+    fn following(&self, a: &str, b: &str, after: (Timestamp, MsgId), limit: usize) -> io::Result<Vec<Record>> {
+        Ok(self.history.following(a, b, after, limit))
+    }
+
+    fn search_messages(&self, key: &str, query: &str, peer: Option<&str>, since: Option<Timestamp>) -> io::Result<Vec<Record>> {
+        Ok(self.history.search(key, query, peer, since))
+    }
+
+    fn expire(&mut self, now: Timestamp, sent_before: Option<Timestamp>) -> io::Result<usize> {
+        let gone = |record: &Record| record.expired(now) || sent_before.is_some_and(|before| record.timestamp < before);
+        for records in self.queued.values_mut() {
+            records.retain(|record| !gone(record));
+        }
+        self.queued.retain(|_, records| !records.is_empty());
+        let removed = self.history.retain(|record| !gone(record))?;
+        let history = &self.history;
+        self.reactions.retain(|id, _| history.get(*id).is_some());
+        Ok(removed)
+    }
+}
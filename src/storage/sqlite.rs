@@ -0,0 +1,442 @@
+use std::io;
+use std::path::Path;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::ban::{Ban, BanTarget, Bans, IpRange};
+use crate::history::Record;
+use crate::nick;
+use crate::search;
+use crate::{Msg, MsgId, Nickname, Timestamp};
+use super::{Account, List, Storage};
+
+// Each entry brings the database from the version before it up to the next one. The version
+// the database is at is kept in `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE accounts (
+         key TEXT PRIMARY KEY,
+         name TEXT NOT NULL,
+         created INTEGER NOT NULL,
+         last_seen INTEGER NOT NULL
+     );
+     CREATE TABLE lists (
+         list INTEGER NOT NULL,
+         owner TEXT NOT NULL,
+         key TEXT NOT NULL,
+         name TEXT NOT NULL,
+         PRIMARY KEY (list, owner, key)
+     );
+     CREATE INDEX lists_key ON lists (list, key);
+     CREATE TABLE bans (
+         key TEXT PRIMARY KEY,
+         ip INTEGER NOT NULL,
+         target TEXT NOT NULL,
+         expires INTEGER
+     );
+     CREATE TABLE queued (
+         to_key TEXT NOT NULL,
+         id INTEGER NOT NULL,
+         sender TEXT NOT NULL,
+         recipient TEXT NOT NULL,
+         timestamp INTEGER NOT NULL,
+         msg TEXT NOT NULL
+     );
+     CREATE INDEX queued_to ON queued (to_key);",
+    "CREATE TABLE messages (
+         id INTEGER PRIMARY KEY,
+         sender TEXT NOT NULL,
+         recipient TEXT NOT NULL,
+         sender_key TEXT NOT NULL,
+         recipient_key TEXT NOT NULL,
+         timestamp INTEGER NOT NULL,
+         msg TEXT NOT NULL
+     );
+     CREATE INDEX messages_between ON messages (sender_key, recipient_key, timestamp);",
+    "ALTER TABLE messages ADD COLUMN expires INTEGER;
+     ALTER TABLE queued ADD COLUMN expires INTEGER;
+     CREATE INDEX messages_timestamp ON messages (timestamp);
+     CREATE INDEX messages_expires ON messages (expires);",
+    // Full-text index over the messages, split into words the same way `search::words` does
+    "CREATE VIRTUAL TABLE messages_text USING fts5 (
+         msg, content = 'messages', content_rowid = 'id', tokenize = 'unicode61 remove_diacritics 0'
+     );
+     INSERT INTO messages_text (rowid, msg) SELECT id, msg FROM messages;
+     CREATE TRIGGER messages_text_insert AFTER INSERT ON messages BEGIN
+         INSERT INTO messages_text (rowid, msg) VALUES (new.id, new.msg);
+     END;
+     CREATE TRIGGER messages_text_delete AFTER DELETE ON messages BEGIN
+         INSERT INTO messages_text (messages_text, rowid, msg) VALUES ('delete', old.id, old.msg);
+     END;
+     CREATE TRIGGER messages_text_update AFTER UPDATE OF msg ON messages BEGIN
+         INSERT INTO messages_text (messages_text, rowid, msg) VALUES ('delete', old.id, old.msg);
+         INSERT INTO messages_text (rowid, msg) VALUES (new.id, new.msg);
+     END;",
+    "CREATE TABLE reactions (
+         message INTEGER NOT NULL,
+         emoji TEXT NOT NULL,
+         key TEXT NOT NULL,
+         name TEXT NOT NULL,
+         PRIMARY KEY (message, emoji, key)
+     );
+     CREATE TRIGGER reactions_delete AFTER DELETE ON messages BEGIN
+         DELETE FROM reactions WHERE message = old.id;
+     END;",
+    "ALTER TABLE accounts ADD COLUMN secret TEXT;",
+];
+
+/// Keeps everything in an SQLite database. Ids and timestamps are stored as the `i64` with
+/// the same bits, since SQLite has no unsigned integers.
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SqliteStorage> {
+        let mut conn = Connection::open(path).map_err(to_io)?;
+        migrate(&mut conn)?;
+        rekey_bans(&mut conn)?;
+        Ok(SqliteStorage { conn })
+    }
+}
+
+// Ip ranges used to be stored the way they were written, with the host bits in them. The
+// same range read back now has them cleared, and has to be found under that key to be lifted.
+fn rekey_bans(conn: &mut Connection) -> io::Result<()> {
+    let tx = conn.transaction().map_err(to_io)?;
+    let bans = {
+        let mut stmt = tx.prepare("SELECT key, target FROM bans WHERE ip").map_err(to_io)?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))).map_err(to_io)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(to_io)?
+    };
+    for (key, target) in bans {
+        let range: IpRange = target.parse().map_err(|_| super::corrupt("ban"))?;
+        let new_key = BanTarget::Ip(range.clone()).key();
+        if new_key != key {
+            tx.execute("UPDATE OR REPLACE bans SET key = ?2, target = ?3 WHERE key = ?1",
+                       params![key, new_key, range.to_string()]).map_err(to_io)?;
+        }
+    }
+    tx.commit().map_err(to_io)
+}
+
+impl Storage for SqliteStorage {
+    fn account(&self, key: &str) -> io::Result<Option<Account>> {
+        self.conn.query_row(
+            "SELECT name, created, last_seen, secret FROM accounts WHERE key = ?1",
+            params![key],
+            |row| Ok(Account {
+                name: row.get(0)?,
+                created: row.get::<_, i64>(1)? as u64,
+                last_seen: row.get::<_, i64>(2)? as u64,
+                secret: row.get(3)?,
+            }),
+        ).optional().map_err(to_io)
+    }
+
+    fn put_account(&mut self, account: &Account) -> io::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO accounts (key, name, created, last_seen, secret) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![nick::key(&account.name), account.name, account.created as i64, account.last_seen as i64, account.secret],
+        ).map_err(to_io)?;
+        Ok(())
+    }
+
+    fn rename_account(&mut self, old_key: &str, new: &str) -> io::Result<()> {
+        let new_key = nick::key(new);
+        let tx = self.conn.transaction().map_err(to_io)?;
+        tx.execute("UPDATE accounts SET key = ?2, name = ?3 WHERE key = ?1", params![old_key, new_key, new])
+            .map_err(to_io)?;
+        tx.execute("UPDATE OR REPLACE lists SET owner = ?2 WHERE owner = ?1", params![old_key, new_key]).map_err(to_io)?;
+        tx.execute("UPDATE OR REPLACE lists SET key = ?2, name = ?3 WHERE key = ?1", params![old_key, new_key, new])
+            .map_err(to_io)?;
+        tx.execute("UPDATE queued SET to_key = ?2 WHERE to_key = ?1", params![old_key, new_key]).map_err(to_io)?;
+        // Queued messages only have the names on them, which can be in any case
+        let queued = {
+            let mut stmt = tx.prepare("SELECT rowid, sender, recipient FROM queued").map_err(to_io)?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))
+                .map_err(to_io)?;
+            rows.collect::<Result<Vec<_>, _>>().map_err(to_io)?
+        };
+        for (rowid, sender, recipient) in queued {
+            if nick::key(&sender) == old_key {
+                tx.execute("UPDATE queued SET sender = ?2 WHERE rowid = ?1", params![rowid, new]).map_err(to_io)?;
+            }
+            if nick::key(&recipient) == old_key {
+                tx.execute("UPDATE queued SET recipient = ?2 WHERE rowid = ?1", params![rowid, new]).map_err(to_io)?;
+            }
+        }
+        tx.execute("UPDATE messages SET sender_key = ?2, sender = ?3 WHERE sender_key = ?1", params![old_key, new_key, new])
+            .map_err(to_io)?;
+        tx.execute("UPDATE messages SET recipient_key = ?2, recipient = ?3 WHERE recipient_key = ?1", params![old_key, new_key, new])
+            .map_err(to_io)?;
+        tx.execute("UPDATE OR REPLACE reactions SET key = ?2, name = ?3 WHERE key = ?1", params![old_key, new_key, new])
+            .map_err(to_io)?;
+        tx.commit().map_err(to_io)
+    }
+
+    fn list(&self, list: List, owner: &str) -> io::Result<Vec<Nickname>> {
+        let mut stmt = self.conn.prepare("SELECT name FROM lists WHERE list = ?1 AND owner = ?2 ORDER BY rowid")
+            .map_err(to_io)?;
+        let names = stmt.query_map(params![list_id(list), owner], |row| row.get(0)).map_err(to_io)?;
+        names.collect::<rusqlite::Result<_>>().map_err(to_io)
+    }
+
+    fn set_list(&mut self, list: List, owner: &str, names: &[Nickname]) -> io::Result<()> {
+        let tx = self.conn.transaction().map_err(to_io)?;
+        tx.execute("DELETE FROM lists WHERE list = ?1 AND owner = ?2", params![list_id(list), owner]).map_err(to_io)?;
+        for name in names {
+            tx.execute(
+                "INSERT OR IGNORE INTO lists (list, owner, key, name) VALUES (?1, ?2, ?3, ?4)",
+                params![list_id(list), owner, nick::key(name), name],
+            ).map_err(to_io)?;
+        }
+        tx.commit().map_err(to_io)
+    }
+
+    fn listed_by(&self, list: List, key: &str) -> io::Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("SELECT owner FROM lists WHERE list = ?1 AND key = ?2").map_err(to_io)?;
+        let owners = stmt.query_map(params![list_id(list), key], |row| row.get(0)).map_err(to_io)?;
+        owners.collect::<rusqlite::Result<_>>().map_err(to_io)
+    }
+
+    fn bans(&self) -> io::Result<Bans> {
+        let mut stmt = self.conn.prepare("SELECT ip, target, expires FROM bans").map_err(to_io)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, bool>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<i64>>(2)?))
+        }).map_err(to_io)?;
+        let mut bans = Vec::new();
+        for row in rows {
+            let (ip, target, expires) = row.map_err(to_io)?;
+            let target = if ip {
+                BanTarget::Ip(target.parse().map_err(|_| super::corrupt("ban"))?)
+            } else {
+                BanTarget::Nick(target)
+            };
+            bans.push(Ban { target, expires: expires.map(|expires| expires as u64) });
+        }
+        Ok(Bans::new(bans))
+    }
+
+    fn add_ban(&mut self, ban: &Ban) -> io::Result<()> {
+        let (ip, target) = match &ban.target {
+            BanTarget::Nick(name) => (false, name.clone()),
+            BanTarget::Ip(range) => (true, range.to_string()),
+        };
+        let tx = self.conn.transaction().map_err(to_io)?;
+        tx.execute("DELETE FROM bans WHERE expires <= ?1", params![crate::now() as i64]).map_err(to_io)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO bans (key, ip, target, expires) VALUES (?1, ?2, ?3, ?4)",
+            params![ban.target.key(), ip, target, ban.expires.map(|expires| expires as i64)],
+        ).map_err(to_io)?;
+        tx.commit().map_err(to_io)
+    }
+
+    fn remove_ban(&mut self, target: &BanTarget) -> io::Result<bool> {
+        let removed = self.conn.execute("DELETE FROM bans WHERE key = ?1", params![target.key()]).map_err(to_io)?;
+        Ok(removed > 0)
+    }
+
+    fn queue(&mut self, to: &str, record: &Record) -> io::Result<()> {
+        self.conn.execute(
+            "INSERT INTO queued (to_key, id, sender, recipient, timestamp, msg, expires) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![to, record.id as i64, record.from, record.to, record.timestamp as i64, record.msg,
+                    record.expires.map(|expires| expires as i64)],
+        ).map_err(to_io)?;
+        Ok(())
+    }
+
+    fn take_queued(&mut self, key: &str) -> io::Result<Vec<Record>> {
+        let tx = self.conn.transaction().map_err(to_io)?;
+        let records = {
+            let mut stmt = tx.prepare(
+                "SELECT id, sender, recipient, timestamp, msg, expires FROM queued WHERE to_key = ?1 ORDER BY rowid"
+            ).map_err(to_io)?;
+            let rows = stmt.query_map(params![key], to_record).map_err(to_io)?;
+            rows.collect::<rusqlite::Result<Vec<Record>>>().map_err(to_io)?
+        };
+        tx.execute("DELETE FROM queued WHERE to_key = ?1", params![key]).map_err(to_io)?;
+        tx.commit().map_err(to_io)?;
+        Ok(records)
+    }
+
+    fn is_queued(&self, key: &str, id: MsgId) -> io::Result<bool> {
+        self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM queued WHERE to_key = ?1 AND id = ?2)",
+            params![key, id as i64],
+            |row| row.get(0),
+        ).map_err(to_io)
+    }
+
+    fn message(&self, id: MsgId) -> io::Result<Option<Record>> {
+        self.conn.query_row(
+            "SELECT id, sender, recipient, timestamp, msg, expires FROM messages WHERE id = ?1",
+            params![id as i64],
+            to_record,
+        ).optional().map_err(to_io)
+    }
+
+    fn add_message(&mut self, record: &Record) -> io::Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO messages (id, sender, recipient, sender_key, recipient_key, timestamp, msg, expires)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![record.id as i64, record.from, record.to, nick::key(&record.from), nick::key(&record.to),
+                    record.timestamp as i64, record.msg, record.expires.map(|expires| expires as i64)],
+        ).map_err(to_io)?;
+        Ok(())
+    }
+
+    fn edit_message(&mut self, id: MsgId, msg: &str) -> io::Result<bool> {
+        let tx = self.conn.transaction().map_err(to_io)?;
+        tx.execute("UPDATE queued SET msg = ?2 WHERE id = ?1", params![id as i64, msg]).map_err(to_io)?;
+        let found = tx.execute("UPDATE messages SET msg = ?2 WHERE id = ?1", params![id as i64, msg]).map_err(to_io)?;
+        tx.commit().map_err(to_io)?;
+        Ok(found > 0)
+    }
+
+    fn delete_message(&mut self, id: MsgId) -> io::Result<bool> {
+        let tx = self.conn.transaction().map_err(to_io)?;
+        tx.execute("DELETE FROM queued WHERE id = ?1", params![id as i64]).map_err(to_io)?;
+        let found = tx.execute("DELETE FROM messages WHERE id = ?1", params![id as i64]).map_err(to_io)?;
+        tx.commit().map_err(to_io)?;
+        Ok(found > 0)
+    }
+
+    fn reactions(&self, id: MsgId) -> io::Result<Vec<(Msg, Vec<Nickname>)>> {
+        let mut stmt = self.conn.prepare("SELECT emoji, name FROM reactions WHERE message = ?1 ORDER BY rowid")
+            .map_err(to_io)?;
+        let rows = stmt.query_map(params![id as i64], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(to_io)?;
+        let mut reactions: Vec<(Msg, Vec<Nickname>)> = Vec::new();
+        for row in rows {
+            let (emoji, name) = row.map_err(to_io)?;
+            match reactions.iter_mut().find(|(e, _)| *e == emoji) {
+                Some((_, names)) => names.push(name),
+                None => reactions.push((emoji, vec![name])),
+            }
+        }
+        Ok(reactions)
+    }
+
+    fn react(&mut self, id: MsgId, emoji: &str, name: &str) -> io::Result<bool> {
+        let added = self.conn.execute(
+            "INSERT OR IGNORE INTO reactions (message, emoji, key, name) VALUES (?1, ?2, ?3, ?4)",
+            params![id as i64, emoji, nick::key(name), name],
+        ).map_err(to_io)?;
+        Ok(added > 0)
+    }
+
+    fn unreact(&mut self, id: MsgId, emoji: &str, key: &str) -> io::Result<bool> {
+        let removed = self.conn.execute(
+            "DELETE FROM reactions WHERE message = ?1 AND emoji = ?2 AND key = ?3",
+            params![id as i64, emoji, key],
+        ).map_err(to_io)?;
+        Ok(removed > 0)
+    }
+
+    fn user_messages(&self, key: &str) -> io::Result<Vec<Record>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, sender, recipient, timestamp, msg, expires FROM messages
+             WHERE sender_key = ?1 OR recipient_key = ?1 ORDER BY timestamp, rowid"
+        ).map_err(to_io)?;
+        let rows = stmt.query_map(params![key], to_record).map_err(to_io)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(to_io)
+    }
+
+    // Positions are compared as the stored `i64`s, which orders ids differently than `u64`s do
+    // but the same way every time, and that is all paging needs
+    fn conversation(&self, a: &str, b: &str, before: Option<(Timestamp, MsgId)>, limit: usize) -> io::Result<Vec<Record>> {
+        let (a, b) = (nick::key(a), nick::key(b));
+        let mut stmt = self.conn.prepare(
+            "SELECT id, sender, recipient, timestamp, msg, expires FROM messages
+             WHERE ((sender_key = ?1 AND recipient_key = ?2) OR (sender_key = ?2 AND recipient_key = ?1))
+               AND (?3 IS NULL OR (timestamp, id) < (?3, ?4))
+             ORDER BY timestamp DESC, id DESC LIMIT ?5"
+        ).map_err(to_io)?;
+        let (timestamp, id) = before.map_or((None, None), |(timestamp, id)| (Some(timestamp as i64), Some(id as i64)));
+        let rows = stmt.query_map(params![a, b, timestamp, id, limit as i64], to_record).map_err(to_io)?;
+        let mut page = rows.collect::<rusqlite::Result<Vec<Record>>>().map_err(to_io)?;
+        page.reverse();
+        Ok(page)
+    }
+
+    fn following(&self, a: &str, b: &str, after: (Timestamp, MsgId), limit: usize) -> io::Result<Vec<Record>> {
+        let (a, b) = (nick::key(a), nick::key(b));
+        let mut stmt = self.conn.prepare(
+            "SELECT id, sender, recipient, timestamp, msg, expires FROM messages
+             WHERE ((sender_key = ?1 AND recipient_key = ?2) OR (sender_key = ?2 AND recipient_key = ?1))
+               AND (timestamp, id) > (?3, ?4)
+             ORDER BY timestamp, id LIMIT ?5"
+        ).map_err(to_io)?;
+        let (timestamp, id) = (after.0 as i64, after.1 as i64);
+        let rows = stmt.query_map(params![a, b, timestamp, id, limit as i64], to_record).map_err(to_io)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(to_io)
+    }
+
+    fn search_messages(&self, key: &str, query: &str, peer: Option<&str>, since: Option<Timestamp>) -> io::Result<Vec<Record>> {
+        // Every word quoted, so nothing in the query is taken for FTS5 syntax
+        let words: Vec<String> = search::words(query).map(|word| format!("\"{}\"", word)).collect();
+        if words.is_empty() { return Ok(Vec::new()); }
+        let mut stmt = self.conn.prepare(
+            "SELECT m.id, m.sender, m.recipient, m.timestamp, m.msg, m.expires
+             FROM messages_text JOIN messages m ON m.id = messages_text.rowid
+             WHERE messages_text MATCH ?1
+               AND (m.sender_key = ?2 OR m.recipient_key = ?2)
+               AND (?3 IS NULL OR (m.sender_key = ?2 AND m.recipient_key = ?3) OR (m.sender_key = ?3 AND m.recipient_key = ?2))
+               AND (?4 IS NULL OR m.timestamp >= ?4)
+             ORDER BY m.timestamp DESC, m.rowid DESC"
+        ).map_err(to_io)?;
+        let params = params![words.join(" "), key, peer.map(nick::key), since.map(|since| since as i64)];
+        let rows = stmt.query_map(params, to_record).map_err(to_io)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(to_io)
+    }
+
+    fn expire(&mut self, now: Timestamp, sent_before: Option<Timestamp>) -> io::Result<usize> {
+        // Comparisons with a missing `expires` or `sent_before` are NULL, which doesn't match
+        let (now, sent_before) = (now as i64, sent_before.map(|before| before as i64));
+        let tx = self.conn.transaction().map_err(to_io)?;
+        tx.execute("DELETE FROM queued WHERE expires <= ?1 OR timestamp < ?2", params![now, sent_before])
+            .map_err(to_io)?;
+        let removed = tx.execute("DELETE FROM messages WHERE expires <= ?1 OR timestamp < ?2", params![now, sent_before])
+            .map_err(to_io)?;
+        tx.commit().map_err(to_io)?;
+        Ok(removed)
+    }
+}
+
+// A row of `id, sender, recipient, timestamp, msg, expires`
+fn to_record(row: &rusqlite::Row) -> rusqlite::Result<Record> {
+    Ok(Record {
+        id: row.get::<_, i64>(0)? as u64,
+        from: row.get(1)?,
+        to: row.get(2)?,
+        timestamp: row.get::<_, i64>(3)? as u64,
+        msg: row.get(4)?,
+        expires: row.get::<_, Option<i64>>(5)?.map(|expires| expires as u64),
+    })
+}
+
+fn migrate(conn: &mut Connection) -> io::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).map_err(to_io)?;
+    if version > MIGRATIONS.len() {
+        return Err(io::Error::other("the database is from a newer version of the server"));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        // Each step and the version bump after it go in together or not at all
+        let tx = conn.transaction().map_err(to_io)?;
+        tx.execute_batch(migration).map_err(to_io)?;
+        tx.pragma_update(None, "user_version", i + 1).map_err(to_io)?;
+        tx.commit().map_err(to_io)?;
+    }
+    Ok(())
+}
+
+fn list_id(list: List) -> i64 {
+    match list {
+        List::Contacts => 0,
+        List::Blocks => 1,
+    }
+}
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}